@@ -0,0 +1,176 @@
+use std::path::PathBuf;
+use clap::builder::BoolishValueParser;
+use clap::Parser;
+use super::Config;
+use crate::net::Cidr;
+
+/// Command-line flags. Each one can also be set through the `WINDROP_*` variable named after it.
+/// Switches take an optional value (true/false, yes/no, on/off or 1/0), so they can turn off
+/// what the config file turns on; given bare they mean true.
+#[derive(Debug, Parser)]
+#[command(name = "windrop", version, about = "Local network file drop server")]
+pub struct Cli {
+    /// TOML config file; defaults to ./windrop.toml when it exists
+    #[arg(long, env = "WINDROP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:8080
+    #[arg(long, env = "WINDROP_BIND")]
+    bind: Option<String>,
+
+    #[arg(long, env = "WINDROP_WORKERS")]
+    workers: Option<usize>,
+
+    /// Seconds uploads in progress may run on after SIGTERM or Ctrl-C
+    #[arg(long, env = "WINDROP_SHUTDOWN_GRACE_SECS")]
+    shutdown_grace_secs: Option<u64>,
+
+    /// Server name shown to clients that discover it on the network
+    #[arg(long, env = "WINDROP_NAME")]
+    name: Option<String>,
+
+    /// Whether to announce the server over mDNS/DNS-SD
+    #[arg(long, env = "WINDROP_MDNS", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    mdns: Option<bool>,
+
+    /// Instead of serving, list the Windrop servers announced on the network, waiting SECS
+    /// seconds for answers, then exit
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "3")]
+    pub browse: Option<u64>,
+
+    /// Directory holding uploaded files and server state
+    #[arg(long, env = "WINDROP_STORAGE_PATH")]
+    storage_path: Option<PathBuf>,
+
+    /// Lifetime of uploads that do not ask for one; 0 keeps them until deleted
+    #[arg(long, env = "WINDROP_DEFAULT_TTL_SECS")]
+    default_ttl_secs: Option<u64>,
+
+    /// Longest lifetime a client may ask for; 0 for no limit
+    #[arg(long, env = "WINDROP_MAX_TTL_SECS")]
+    max_ttl_secs: Option<u64>,
+
+    #[arg(long, env = "WINDROP_REAPER_INTERVAL_SECS")]
+    reaper_interval_secs: Option<u64>,
+
+    /// Discard upload sessions that receive no chunk for this long; 0 keeps them until restart
+    #[arg(long, env = "WINDROP_UPLOAD_IDLE_SECS")]
+    upload_idle_secs: Option<u64>,
+
+    /// Largest single upload in bytes; 0 for no limit
+    #[arg(long, env = "WINDROP_MAX_FILE_SIZE")]
+    max_file_size: Option<u64>,
+
+    /// Total bytes stored across all devices; 0 for no limit
+    #[arg(long, env = "WINDROP_GLOBAL_QUOTA")]
+    global_quota: Option<u64>,
+
+    /// Bytes stored per device; 0 for no limit
+    #[arg(long, env = "WINDROP_DEVICE_QUOTA")]
+    device_quota: Option<u64>,
+
+    /// Requests a client may make per rate limit window
+    #[arg(long, env = "WINDROP_RATE_LIMIT_MAX_REQUESTS")]
+    rate_limit_max_requests: Option<usize>,
+
+    #[arg(long, env = "WINDROP_RATE_LIMIT_WINDOW_SECS")]
+    rate_limit_window_secs: Option<u64>,
+
+    #[arg(long, env = "WINDROP_HEARTBEAT_INTERVAL_SECS")]
+    heartbeat_interval_secs: Option<u64>,
+
+    #[arg(long, env = "WINDROP_CLIENT_TIMEOUT_SECS")]
+    client_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "WINDROP_DISCOVERY_INTERVAL_SECS")]
+    discovery_interval_secs: Option<u64>,
+
+    /// Largest WebSocket message a client may send, in bytes
+    #[arg(long, env = "WINDROP_WS_MAX_FRAME_SIZE")]
+    ws_max_frame_size: Option<usize>,
+
+    /// Seconds a receiver has to answer a transfer request
+    #[arg(long, env = "WINDROP_TRANSFER_REQUEST_TIMEOUT_SECS")]
+    transfer_request_timeout_secs: Option<u64>,
+
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, env = "WINDROP_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching --tls-cert
+    #[arg(long, env = "WINDROP_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS with a self-signed certificate, generating it on first start
+    #[arg(long, env = "WINDROP_TLS_SELF_SIGNED", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    tls_self_signed: Option<bool>,
+
+    /// Comma-separated proxy addresses or CIDR ranges whose forwarding headers are believed
+    #[arg(long, env = "WINDROP_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxies: Option<Vec<Cidr>>,
+
+    /// Expect connections to start with a PROXY protocol header from a trusted proxy
+    #[arg(long, env = "WINDROP_PROXY_PROTOCOL", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    proxy_protocol: Option<bool>,
+
+    /// Comma-separated addresses or CIDR ranges allowed to use the server
+    #[arg(long, env = "WINDROP_ALLOW", value_delimiter = ',')]
+    allow: Option<Vec<Cidr>>,
+
+    /// Comma-separated addresses or CIDR ranges refused even when allowed
+    #[arg(long, env = "WINDROP_DENY", value_delimiter = ',')]
+    deny: Option<Vec<Cidr>>,
+
+    /// Only serve clients on private, link-local and loopback addresses (and --allow)
+    #[arg(long, env = "WINDROP_PRIVATE_NETWORKS_ONLY", value_parser = BoolishValueParser::new(), num_args = 0..=1, default_missing_value = "true")]
+    private_networks_only: Option<bool>,
+
+    /// Comma-separated web origins allowed to call the API from a browser, or *
+    #[arg(long, env = "WINDROP_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Option<Vec<String>>,
+}
+
+impl Cli {
+    /// Overrides the values in `config` that were given as flags or environment variables.
+    pub fn apply(&self, config: &mut Config) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+
+        set(&mut config.server.bind, &self.bind);
+        set(&mut config.server.workers, &self.workers);
+        set(&mut config.server.name, &self.name);
+        set(&mut config.server.shutdown_grace_secs, &self.shutdown_grace_secs);
+        set(&mut config.mdns.enabled, &self.mdns);
+        set(&mut config.storage.path, &self.storage_path);
+        set(&mut config.storage.default_ttl_secs, &self.default_ttl_secs);
+        set(&mut config.storage.max_ttl_secs, &self.max_ttl_secs);
+        set(&mut config.storage.reaper_interval_secs, &self.reaper_interval_secs);
+        set(&mut config.storage.upload_idle_secs, &self.upload_idle_secs);
+        set(&mut config.storage.max_file_size, &self.max_file_size);
+        set(&mut config.storage.global_quota, &self.global_quota);
+        set(&mut config.storage.device_quota, &self.device_quota);
+        set(&mut config.rate_limit.max_requests, &self.rate_limit_max_requests);
+        set(&mut config.rate_limit.window_secs, &self.rate_limit_window_secs);
+        set(&mut config.websocket.heartbeat_interval_secs, &self.heartbeat_interval_secs);
+        set(&mut config.websocket.client_timeout_secs, &self.client_timeout_secs);
+        set(&mut config.websocket.discovery_interval_secs, &self.discovery_interval_secs);
+        set(&mut config.websocket.max_frame_size, &self.ws_max_frame_size);
+        set(&mut config.websocket.transfer_request_timeout_secs, &self.transfer_request_timeout_secs);
+        if self.tls_cert.is_some() {
+            config.tls.cert_path = self.tls_cert.clone();
+        }
+        if self.tls_key.is_some() {
+            config.tls.key_path = self.tls_key.clone();
+        }
+        set(&mut config.tls.self_signed, &self.tls_self_signed);
+        set(&mut config.proxy.trusted, &self.trusted_proxies);
+        set(&mut config.proxy.proxy_protocol, &self.proxy_protocol);
+        set(&mut config.access.allow, &self.allow);
+        set(&mut config.access.deny, &self.deny);
+        set(&mut config.access.private_networks_only, &self.private_networks_only);
+        set(&mut config.access.cors_origins, &self.cors_origins);
+    }
+}
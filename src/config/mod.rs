@@ -0,0 +1,576 @@
+mod cli;
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Deserialize;
+use crate::models::usage::QuotaLimits;
+use crate::net::Cidr;
+
+pub use cli::Cli;
+
+/// Read from the working directory when no `--config` is given
+const DEFAULT_CONFIG_FILE: &str = "windrop.toml";
+
+/// Server settings. Each value comes from, in increasing precedence: the built-in default, the
+/// TOML file, a `WINDROP_*` environment variable, and a command-line flag.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub rate_limit: RateLimitConfig,
+    pub websocket: WebSocketConfig,
+    pub tls: TlsConfig,
+    pub mdns: MdnsConfig,
+    pub proxy: ProxyConfig,
+    pub access: AccessConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub workers: usize,
+    /// Shown to clients that discover the server on the network
+    pub name: String,
+    /// How long uploads in progress may run on after a shutdown signal
+    pub shutdown_grace_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".to_string(),
+            workers: 4,
+            name: default_server_name(),
+            shutdown_grace_secs: 30,
+        }
+    }
+}
+
+/// Sizes are in bytes and times in seconds. A size or TTL of 0 means unlimited.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Relative paths are resolved against the working directory
+    pub path: PathBuf,
+    pub default_ttl_secs: u64,
+    /// Longest `ttl_secs` a client may ask for
+    pub max_ttl_secs: u64,
+    pub reaper_interval_secs: u64,
+    /// Upload sessions that receive no chunk for this long are discarded by the reaper
+    pub upload_idle_secs: u64,
+    pub max_file_size: u64,
+    pub global_quota: u64,
+    pub device_quota: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("file_storage"),
+            default_ttl_secs: 7 * 24 * 60 * 60,
+            max_ttl_secs: 365 * 24 * 60 * 60,
+            reaper_interval_secs: 60,
+            upload_idle_secs: 60 * 60,
+            max_file_size: 4 * 1024 * 1024 * 1024,
+            global_quota: 0,
+            device_quota: 0,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+impl StorageConfig {
+    pub fn default_ttl(&self) -> Option<Duration> {
+        non_zero(self.default_ttl_secs).map(Duration::from_secs)
+    }
+
+    pub fn max_ttl(&self) -> Option<Duration> {
+        non_zero(self.max_ttl_secs).map(Duration::from_secs)
+    }
+
+    pub fn reaper_interval(&self) -> Duration {
+        Duration::from_secs(self.reaper_interval_secs)
+    }
+
+    pub fn upload_idle(&self) -> Option<Duration> {
+        non_zero(self.upload_idle_secs).map(Duration::from_secs)
+    }
+
+    /// The size limits uploads are held to. A `max_file_size` above a quota is capped at the
+    /// smallest quota, as no upload could be larger anyway.
+    pub fn limits(&self) -> QuotaLimits {
+        let global_quota = non_zero(self.global_quota);
+        let device_quota = non_zero(self.device_quota);
+        let max_file_size = non_zero(self.max_file_size)
+            .map(|max| [global_quota, device_quota].into_iter().flatten().fold(max, u64::min));
+        QuotaLimits {
+            max_file_size,
+            global_quota,
+            device_quota,
+        }
+    }
+}
+
+/// Requests allowed per client IP. `max_requests` and `window_secs` apply to requests no entry
+/// in `routes` matches.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_requests: usize,
+    pub window_secs: u64,
+    /// Checked in order; the first entry matching a request's path and method applies
+    pub routes: Vec<RouteRateLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 100,
+            window_secs: 60,
+            routes: vec![
+                // Each upgrade holds a session and starts pairing, so keep reconnect storms short
+                RouteRateLimit::new("/api/ws", &["GET"], 10, 60),
+                // Resumable uploads arrive as many small chunks
+                RouteRateLimit::new("/api/uploads/", &["PUT"], 1200, 60),
+                // Range requests make one download many requests
+                RouteRateLimit::new("/api/files/", &["GET", "HEAD"], 600, 60),
+            ],
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRateLimit {
+    /// Path prefix the limit applies to
+    pub path: String,
+    /// Methods the limit applies to; every method when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// `0` exempts matching requests from rate limiting
+    pub max_requests: usize,
+    pub window_secs: u64,
+}
+
+impl RouteRateLimit {
+    fn new(path: &str, methods: &[&str], max_requests: usize, window_secs: u64) -> Self {
+        Self {
+            path: path.to_string(),
+            methods: methods.iter().map(|method| method.to_string()).collect(),
+            max_requests,
+            window_secs,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub heartbeat_interval_secs: u64,
+    /// A client that stays silent this long is disconnected
+    pub client_timeout_secs: u64,
+    /// How often devices that did not reconnect in time are forgotten and announced as left
+    pub discovery_interval_secs: u64,
+    /// Largest message a client may send, in bytes, whether in one frame or in fragments
+    pub max_frame_size: usize,
+    /// A transfer request the receiver has not answered by then fails
+    pub transfer_request_timeout_secs: u64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: 5,
+            client_timeout_secs: 10,
+            discovery_interval_secs: 10,
+            max_frame_size: 1024 * 1024,
+            transfer_request_timeout_secs: 120,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn discovery_interval(&self) -> Duration {
+        Duration::from_secs(self.discovery_interval_secs)
+    }
+
+    pub fn transfer_request_timeout(&self) -> Duration {
+        Duration::from_secs(self.transfer_request_timeout_secs)
+    }
+}
+
+/// HTTPS is served when a certificate is configured or `self_signed` is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain; relative paths are resolved against the working directory
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key
+    pub key_path: Option<PathBuf>,
+    /// Generate a self-signed certificate on first start if the files do not exist yet. Without
+    /// explicit paths it is kept in the `tls` folder of the storage directory.
+    pub self_signed: bool,
+    /// Host names and IP addresses the generated certificate is issued for
+    pub self_signed_names: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            self_signed: false,
+            self_signed_names: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+        }
+    }
+}
+
+/// Announcement of the server as a `_windrop._tcp` DNS-SD service.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsConfig {
+    pub enabled: bool,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Reverse proxies in front of the server. Client addresses they report are only believed
+/// when the connection comes from one of them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Addresses or CIDR ranges of trusted proxies, e.g. `"10.0.0.0/8"`
+    pub trusted: Vec<Cidr>,
+    /// Expect every connection to start with a PROXY protocol v1 or v2 header
+    pub proxy_protocol: bool,
+}
+
+/// Which clients may use the server, by the address resolved through trusted proxies, and which
+/// web origins may call it from a browser.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Addresses or CIDR ranges allowed in; everyone when empty and `private_networks_only` is off
+    pub allow: Vec<Cidr>,
+    /// Addresses or CIDR ranges refused even when `allow` covers them
+    pub deny: Vec<Cidr>,
+    /// Add private, link-local and loopback ranges to `allow`
+    pub private_networks_only: bool,
+    /// Origins such as `https://drop.example.com`, or `"*"` for any origin without credentials.
+    /// Empty allows no cross-origin access.
+    pub cors_origins: Vec<String>,
+}
+
+impl Config {
+    /// Builds the configuration from the parsed arguments, environment and config file.
+    pub fn load(cli: &Cli) -> io::Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        cli.apply(&mut config);
+
+        let cwd = std::env::current_dir()?;
+        config.storage.path = cwd.join(&config.storage.path);
+        if config.tls.self_signed && config.tls.cert_path.is_none() && config.tls.key_path.is_none() {
+            let tls_dir = config.storage.path.join("tls");
+            config.tls.cert_path = Some(tls_dir.join("cert.pem"));
+            config.tls.key_path = Some(tls_dir.join("key.pem"));
+        }
+        for path in [&mut config.tls.cert_path, &mut config.tls.key_path].into_iter().flatten() {
+            *path = cwd.join(&*path);
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to read config file {}: {}", path.display(), e))
+        })?;
+        Self::from_toml(&contents).map_err(|e| {
+            io::Error::new(e.kind(), format!("Invalid config file {}: {}", path.display(), e))
+        })
+    }
+
+    pub fn from_toml(contents: &str) -> io::Result<Self> {
+        toml::from_str(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.message().to_string()))
+    }
+
+    pub fn bind_addr(&self) -> io::Result<SocketAddr> {
+        self.server.bind.parse().map_err(|_| {
+            invalid(format!("server.bind must be an address like 127.0.0.1:8080, got {:?}", self.server.bind))
+        })
+    }
+
+    /// Rejects settings the server cannot run with, naming the offending key.
+    pub fn validate(&self) -> io::Result<()> {
+        self.bind_addr()?;
+
+        let positive = [
+            ("server.workers", self.server.workers as u64),
+            ("storage.reaper_interval_secs", self.storage.reaper_interval_secs),
+            ("rate_limit.max_requests", self.rate_limit.max_requests as u64),
+            ("rate_limit.window_secs", self.rate_limit.window_secs),
+            ("websocket.heartbeat_interval_secs", self.websocket.heartbeat_interval_secs),
+            ("websocket.discovery_interval_secs", self.websocket.discovery_interval_secs),
+            ("websocket.max_frame_size", self.websocket.max_frame_size as u64),
+            ("websocket.transfer_request_timeout_secs", self.websocket.transfer_request_timeout_secs),
+        ];
+        if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(invalid(format!("{} must be greater than zero", key)));
+        }
+
+        if self.websocket.client_timeout_secs <= self.websocket.heartbeat_interval_secs {
+            return Err(invalid(
+                "websocket.client_timeout_secs must be longer than websocket.heartbeat_interval_secs",
+            ));
+        }
+
+        for (i, route) in self.rate_limit.routes.iter().enumerate() {
+            let key = format!("rate_limit.routes[{}]", i);
+            if !route.path.starts_with('/') {
+                return Err(invalid(format!("{}.path must start with '/'", key)));
+            }
+            if let Some(method) = route
+                .methods
+                .iter()
+                .find(|method| method.parse::<actix_web::http::Method>().is_err() || method.to_uppercase() != **method)
+            {
+                return Err(invalid(format!("{}.methods has an invalid method {:?}", key, method)));
+            }
+            if route.max_requests > 0 && route.window_secs == 0 {
+                return Err(invalid(format!("{}.window_secs must be greater than zero", key)));
+            }
+        }
+
+        if self.server.name.trim().is_empty() {
+            return Err(invalid("server.name must not be empty"));
+        }
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            return Err(invalid("tls.cert_path and tls.key_path must be set together"));
+        }
+        if self.tls.self_signed && self.tls.self_signed_names.is_empty() {
+            return Err(invalid("tls.self_signed_names must name at least one host"));
+        }
+
+        if self.proxy.proxy_protocol && self.proxy.trusted.is_empty() {
+            return Err(invalid("proxy.proxy_protocol requires proxy.trusted to list the proxies"));
+        }
+
+        for origin in &self.access.cors_origins {
+            if origin != "*" && !is_origin(origin) {
+                return Err(invalid(format!(
+                    "access.cors_origins entry {:?} must be \"*\" or an origin like https://host:port",
+                    origin
+                )));
+            }
+        }
+        if self.access.cors_origins.len() > 1 && self.access.cors_origins.iter().any(|origin| origin == "*") {
+            return Err(invalid("access.cors_origins cannot combine \"*\" with specific origins"));
+        }
+
+        if let Some(max) = non_zero(self.storage.max_ttl_secs) {
+            if self.storage.default_ttl_secs > max {
+                return Err(invalid("storage.default_ttl_secs must not exceed storage.max_ttl_secs"));
+            }
+        }
+
+        for (key, quota) in [
+            ("storage.global_quota", self.storage.global_quota),
+            ("storage.device_quota", self.storage.device_quota),
+        ] {
+            if quota > 0 && quota < self.storage.max_file_size {
+                log::warn!(
+                    "{} ({} bytes) is smaller than storage.max_file_size ({} bytes); uploads are limited to the quota",
+                    key,
+                    quota,
+                    self.storage.max_file_size
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `scheme://host[:port]`, without a path or trailing slash, as browsers send in `Origin`.
+fn is_origin(origin: &str) -> bool {
+    match origin.split_once("://") {
+        Some((scheme, host)) => {
+            matches!(scheme, "http" | "https")
+                && !host.is_empty()
+                && !host.contains(['/', '?', '#', ' '])
+        }
+        None => false,
+    }
+}
+
+/// `windrop-<host name>`, so servers on the same network announce different instance names.
+/// Falls back to a random suffix when the host name cannot be found.
+fn default_server_name() -> String {
+    let host = ["HOSTNAME", "COMPUTERNAME"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .chain(std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().split('.').next().unwrap_or_default().to_string())
+        .find(|name| !name.is_empty());
+    let suffix = host.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()[..8].to_string());
+    format!("windrop-{}", suffix)
+}
+
+fn non_zero(value: u64) -> Option<u64> {
+    (value > 0).then_some(value)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers_and_validation() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            bind = "0.0.0.0:9000"
+
+            [storage]
+            max_file_size = 0
+            device_quota = 1000
+
+            [websocket]
+            heartbeat_interval_secs = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.workers, 4);
+        assert!(config.server.name.len() > "windrop-".len() && config.server.name.starts_with("windrop-"));
+        assert_eq!(config.storage.limits().max_file_size, None);
+        assert_eq!(config.storage.limits().device_quota, Some(1000));
+        // A quota below the file size limit caps it rather than stopping the server
+        let capped = Config::from_toml("[storage]\nmax_file_size = 5000\ndevice_quota = 1000\nglobal_quota = 3000").unwrap();
+        capped.validate().unwrap();
+        assert_eq!(capped.storage.limits().max_file_size, Some(1000));
+        assert_eq!(config.websocket.heartbeat_interval(), Duration::from_secs(2));
+        config.validate().unwrap();
+
+        let cli = <Cli as clap::Parser>::try_parse_from(["windrop", "--bind", "127.0.0.1:7000", "--workers", "2"]).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.bind_addr().unwrap(), "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.server.workers, 2);
+
+        config.websocket.client_timeout_secs = 1;
+        assert!(config.validate().is_err());
+
+        config.websocket.client_timeout_secs = 10;
+        config.tls.cert_path = Some(PathBuf::from("cert.pem"));
+        assert!(config.validate().is_err());
+
+        let config = Config::from_toml(
+            r#"
+            [[rate_limit.routes]]
+            path = "/metrics"
+            max_requests = 0
+            window_secs = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit.routes.len(), 1);
+        config.validate().unwrap();
+        assert!(Config::from_toml("[[rate_limit.routes]]\npath = \"/api\"\nmethods = [\"get\"]\nmax_requests = 1\nwindow_secs = 1")
+            .unwrap()
+            .validate()
+            .is_err());
+
+        let config = Config::from_toml("[proxy]\ntrusted = [\"10.0.0.0/8\", \"::1\"]\nproxy_protocol = true").unwrap();
+        assert!(config.proxy.trusted[0].contains("10.1.2.3".parse().unwrap()));
+        config.validate().unwrap();
+        assert!(Config::from_toml("[proxy]\nproxy_protocol = true").unwrap().validate().is_err());
+        assert!(Config::from_toml("[proxy]\ntrusted = [\"10.0.0.0/40\"]").is_err());
+
+        let config = Config::from_toml("[access]\ncors_origins = [\"https://drop.lan:8443\", \"http://localhost\"]").unwrap();
+        config.validate().unwrap();
+        for origins in [r#"["https://drop.lan/"]"#, r#"["drop.lan"]"#, r#"["*", "http://localhost"]"#] {
+            let toml = format!("[access]\ncors_origins = {}", origins);
+            assert!(Config::from_toml(&toml).unwrap().validate().is_err(), "{}", origins);
+        }
+
+        assert!(Config::from_toml("[storage]\ndefault_ttl_secs = 100\nmax_ttl_secs = 10").unwrap().validate().is_err());
+        Config::from_toml("[storage]\ndefault_ttl_secs = 100\nmax_ttl_secs = 0").unwrap().validate().unwrap();
+
+        assert!(Config::from_toml("[server]\nport = 1").is_err());
+        assert!(Config::from_toml("[server]\nbind = \"nowhere\"").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_environment_sits_between_file_and_flags() {
+        // No other test sets these, so changing the process environment is safe here
+        std::env::set_var("WINDROP_PROXY_PROTOCOL", "off");
+        std::env::set_var("WINDROP_PRIVATE_NETWORKS_ONLY", "yes");
+        std::env::set_var("WINDROP_DEVICE_QUOTA", "2000");
+        let toml = "[storage]\ndevice_quota = 1000\n[proxy]\nproxy_protocol = true\n[access]\nprivate_networks_only = false";
+
+        let mut config = Config::from_toml(toml).unwrap();
+        let cli = <Cli as clap::Parser>::try_parse_from(["windrop"]).unwrap();
+        cli.apply(&mut config);
+        assert!(!config.proxy.proxy_protocol);
+        assert!(config.access.private_networks_only);
+        assert_eq!(config.storage.device_quota, 2000);
+
+        let mut config = Config::from_toml(toml).unwrap();
+        let cli = <Cli as clap::Parser>::try_parse_from([
+            "windrop",
+            "--proxy-protocol",
+            "--private-networks-only",
+            "0",
+            "--device-quota",
+            "3000",
+        ])
+        .unwrap();
+        cli.apply(&mut config);
+        assert!(config.proxy.proxy_protocol);
+        assert!(!config.access.private_networks_only);
+        assert_eq!(config.storage.device_quota, 3000);
+
+        for name in ["WINDROP_PROXY_PROTOCOL", "WINDROP_PRIVATE_NETWORKS_ONLY", "WINDROP_DEVICE_QUOTA"] {
+            std::env::remove_var(name);
+        }
+    }
+}
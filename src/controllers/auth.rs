@@ -0,0 +1,53 @@
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+
+use crate::middleware::logger::RequestDevice;
+use crate::models::response::ApiResponse;
+use crate::services::discovery_service::DiscoveryService;
+
+/// Resolves the paired device behind a request from an `Authorization: Bearer <token>`
+/// header. Requests without the header are anonymous; an unknown token is rejected with a 401.
+/// The device is recorded on the request for the access log.
+pub fn request_device(
+    req: &HttpRequest,
+    discovery_service: &DiscoveryService,
+) -> Result<Option<String>, HttpResponse> {
+    let Some(value) = req.headers().get(AUTHORIZATION) else {
+        return Ok(None);
+    };
+
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| discovery_service.authenticate(token.trim()))
+        .map(|device_id| {
+            req.extensions_mut().insert(RequestDevice(device_id.clone()));
+            Some(device_id)
+        })
+        .ok_or_else(|| {
+            HttpResponse::Unauthorized().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                "Unknown device token",
+                None,
+            ))
+        })
+}
+
+/// Like [`request_device`], but a request without a token is rejected with a 401 saying that
+/// `what` needs one.
+pub fn require_device(
+    req: &HttpRequest,
+    discovery_service: &DiscoveryService,
+    what: &str,
+) -> Result<String, HttpResponse> {
+    request_device(req, discovery_service)?.ok_or_else(|| {
+        HttpResponse::Unauthorized().json(ApiResponse::<()>::new(
+            1,
+            "error",
+            &format!("{} needs a device token", what),
+            None,
+        ))
+    })
+}
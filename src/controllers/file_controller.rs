@@ -1,20 +1,130 @@
-use actix_web::{web, HttpResponse, Error, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Error, Result};
+use actix_web::http::StatusCode;
 use actix_multipart::Multipart;
-use futures_util::StreamExt;
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use crate::controllers::auth::{request_device, require_device};
+use crate::services::discovery_service::DiscoveryService;
 use crate::services::file_service::FileService;
+use crate::services::metrics_service::DownloadTimer;
+use crate::websocket::transfers::TransferManager;
+use crate::models::file::{File, FileListQuery, RetentionOptions, UploadTransfer};
+use crate::openapi::{FileContents, UploadForm};
+use utoipa::TupleUnit;
 use crate::models::response::ApiResponse;
-use actix_web::http::header::{ContentDisposition, DispositionType, DispositionParam};
-use tokio::io::BufReader;
+use actix_web::http::header::{
+    ContentDisposition, DispositionType, DispositionParam, ETag, EntityTag, Header, HttpDate, IfRange,
+    LastModified, Range,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::File as TokioFile;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
 use tokio_util::io::ReaderStream;
 
+/// Uploads a file as the first part of a multipart form.
+#[utoipa::path(
+    post,
+    path = "/api/upload",
+    tag = "files",
+    params(
+        RetentionOptions,
+        UploadTransfer,
+        ("Digest" = Option<String>, Header, description = "`sha-256=<base64>` of the file; the upload is discarded unless it matches"),
+        ("Upload-Length" = Option<u64>, Header, description = "Size of the file, checked against size limits and quotas before the body is read"),
+    ),
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "File stored", body = ApiResponse<File>),
+        (status = 400, description = "No file given, or a malformed `Digest` header or retention option", body = ApiResponse<TupleUnit>),
+        (status = 401, description = "Unknown device token, or none given with `transfer_id`", body = ApiResponse<TupleUnit>),
+        (status = 409, description = "`transfer_id` is not an accepted transfer this device sends", body = ApiResponse<TupleUnit>),
+        (status = 413, description = "File too large or quota exceeded", body = ApiResponse<TupleUnit>),
+        (status = 422, description = "Contents do not match the `Digest` header", body = ApiResponse<TupleUnit>),
+        (status = 503, description = "Server is shutting down", body = ApiResponse<TupleUnit>),
+    ),
+    security((), ("device_token" = [])),
+)]
 pub async fn upload_file(
+    req: HttpRequest,
     mut payload: Multipart, 
-    file_service: web::Data<FileService>
+    retention: web::Query<RetentionOptions>,
+    transfer: web::Query<UploadTransfer>,
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+    transfers: web::Data<Arc<TransferManager>>,
 ) -> Result<HttpResponse, Error> {
-    while let Some(item) = payload.next().await {
+    let owner = match request_device(&req, &discovery_service) {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+
+    let expected_sha256 = match req.headers().get("Digest") {
+        Some(value) => match value.to_str().ok().and_then(parse_digest_header) {
+            Some(digest) => Some(digest),
+            None => {
+                return Ok(HttpResponse::BadRequest().json(ApiResponse::<()>::new(
+                    1,
+                    "error",
+                    "Digest header must be of the form sha-256=<base64>",
+                    None,
+                )));
+            }
+        },
+        None => None,
+    };
+
+    // A client that announces the file size is turned away before any of the body is read.
+    // Without Upload-Length the body length stands in; it is a little over the file size.
+    let header_size = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    let declared_size = header_size("Upload-Length");
+    let expected_size = declared_size.or_else(|| header_size("Content-Length"));
+    if let Some(size) = expected_size {
+        if let Err(e) = file_service.upload_allowance(owner.as_deref(), size) {
+            return Ok(payload_too_large(e));
+        }
+    }
+
+    if let Some(transfer_id) = &transfer.transfer_id {
+        let Some(owner) = &owner else {
+            return Ok(HttpResponse::Unauthorized().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                "Uploads for a transfer need a device token",
+                None,
+            )));
+        };
+        if let Err(e) = transfers.begin_upload(transfer_id, owner, declared_size) {
+            return Ok(HttpResponse::Conflict().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                &format!("Upload rejected: {}", e),
+                None,
+            )));
+        }
+    }
+    let on_progress = |bytes| {
+        if let Some(transfer_id) = &transfer.transfer_id {
+            transfers.record_bytes(transfer_id, bytes);
+        }
+    };
+
+    if let Some(item) = payload.next().await {
         let field = item?;
         
-        match file_service.save_file(field).await {
+        match file_service
+            .save_file(field, expected_size, expected_sha256.as_deref(), &retention, owner.as_deref(), on_progress)
+            .await
+        {
             Ok(file) => {
                 let response = ApiResponse::new(
                     0,
@@ -24,6 +134,33 @@ pub async fn upload_file(
                 );
                 return Ok(HttpResponse::Created().json(response));
             }
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Ok(HttpResponse::BadRequest().json(ApiResponse::<()>::new(
+                    1,
+                    "error",
+                    &format!("Upload rejected: {}", e),
+                    None,
+                )));
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::FileTooLarge | io::ErrorKind::QuotaExceeded) => {
+                return Ok(payload_too_large(e));
+            }
+            Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {
+                return Ok(HttpResponse::ServiceUnavailable().json(ApiResponse::<()>::new(
+                    1,
+                    "error",
+                    &format!("Upload rejected: {}", e),
+                    None,
+                )));
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Ok(HttpResponse::UnprocessableEntity().json(ApiResponse::<()>::new(
+                    1,
+                    "error",
+                    &format!("Upload rejected: {}", e),
+                    None,
+                )));
+            }
             Err(e) => {
                 return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()>::new(
                     1,
@@ -43,36 +180,170 @@ pub async fn upload_file(
     )))
 }
 
+fn payload_too_large(e: io::Error) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(ApiResponse::<()>::new(
+        1,
+        "error",
+        &format!("Upload rejected: {}", e),
+        None,
+    ))
+}
+
+/// Downloads a file, optionally in byte ranges.
+///
+/// Downloads that start at the beginning of the file count towards its `max_downloads`.
+#[utoipa::path(
+    get,
+    path = "/api/files/{id}",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "File id"),
+        ("Range" = Option<String>, Header, description = "One or more byte ranges, e.g. `bytes=0-1023`"),
+        ("If-Range" = Option<String>, Header, description = "ETag or date the ranges are conditional on; the whole file is sent if it no longer matches"),
+    ),
+    responses(
+        (status = 200, description = "File contents", content_type = "application/octet-stream", body = FileContents,
+            headers(
+                ("ETag" = String, description = "SHA-256 of the contents"),
+                ("Digest" = String, description = "`sha-256=<base64>` of the contents"),
+            )),
+        (status = 206, description = "The requested range, or `multipart/byteranges` when several were requested", content_type = "application/octet-stream", body = FileContents),
+        (status = 404, description = "File not found, expired, or its download allowance is used up", body = ApiResponse<TupleUnit>),
+        (status = 416, description = "Requested range not satisfiable", body = ApiResponse<TupleUnit>),
+    ),
+)]
 pub async fn get_file(
+    req: HttpRequest,
     file_id: web::Path<String>,
     file_service: web::Data<FileService>,
 ) -> Result<HttpResponse, Error> {
     match file_service.read_file(&file_id).await {
-        Ok((file_info, file_handle)) => {
+        Ok((file_info, mut file_handle)) => {
             // Get file size
             let metadata = file_handle.metadata().await?;
             let file_size = metadata.len();
+            let last_modified = metadata.modified().ok();
 
-            // Create buffered reader with a reasonable buffer size
-            let reader = BufReader::with_capacity(8192, file_handle);
-            
-            // Create stream with chunks
-            let stream = ReaderStream::new(reader);
-            
             // Determine content type
             let content_type = mime_guess::from_path(&file_info.filename)
                 .first_or_octet_stream();
 
-            // Build response with proper headers
-            Ok(HttpResponse::Ok()
-                .insert_header(("Content-Type", content_type.as_ref()))
-                .insert_header(("Content-Length", file_size.to_string()))
+            let mut builder = HttpResponse::Ok();
+            builder
                 .insert_header(ContentDisposition {
                     disposition: DispositionType::Attachment,
-                    parameters: vec![DispositionParam::Filename(file_info.filename)],
+                    parameters: vec![DispositionParam::Filename(file_info.filename.clone())],
                 })
-                .insert_header(("Accept-Ranges", "bytes"))
-                .streaming(stream))
+                .insert_header(("Accept-Ranges", "bytes"));
+            if let Some(modified) = last_modified {
+                builder.insert_header(LastModified(modified.into()));
+            }
+            let etag = file_info.sha256.as_ref().map(|sha256| EntityTag::new_strong(sha256.clone()));
+            if let Some(etag) = &etag {
+                builder.insert_header(ETag(etag.clone()));
+            }
+            if let Some(digest) = file_info.sha256.as_deref().and_then(digest_header_value) {
+                builder.insert_header(("Digest", digest));
+            }
+
+            let ranges = match requested_ranges(&req, file_size, last_modified, etag.as_ref()) {
+                RangeRequest::Full => Vec::new(),
+                RangeRequest::Partial(ranges) => ranges,
+                RangeRequest::Unsatisfiable => {
+                    return Ok(HttpResponse::RangeNotSatisfiable()
+                        .insert_header(("Content-Range", format!("bytes */{}", file_size)))
+                        .json(ApiResponse::<()>::new(
+                            1,
+                            "error",
+                            "Requested range not satisfiable",
+                            None,
+                        )));
+                }
+            };
+
+            // Resumed or seeking range requests only count when they start from the beginning.
+            // Counting can still refuse the download if others used up the allowance meanwhile.
+            if ranges.first().is_none_or(|(start, _)| *start == 0) {
+                match file_service.record_download(&file_info.id) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        return Ok(HttpResponse::NotFound().json(ApiResponse::<()>::new(
+                            1,
+                            "error",
+                            &format!("File error: {}", e),
+                            None,
+                        )));
+                    }
+                    Err(e) => {
+                        log::error!("Failed to record download of {}: {}", file_info.id, e);
+                        return Ok(HttpResponse::InternalServerError().json(ApiResponse::<()>::new(
+                            1,
+                            "error",
+                            "Failed to record download",
+                            None,
+                        )));
+                    }
+                }
+            }
+
+            let timer = file_service.metrics().download_timer();
+            match ranges.as_slice() {
+                [] => {
+                    // Create buffered reader with a reasonable buffer size
+                    let reader = BufReader::with_capacity(8192, file_handle);
+
+                    // Create stream with chunks
+                    let stream = ReaderStream::new(reader);
+
+                    // Build response with proper headers
+                    Ok(builder
+                        .insert_header(("Content-Type", content_type.as_ref()))
+                        .no_chunking(file_size)
+                        .streaming(metered(stream, timer)))
+                }
+                [(start, end)] => {
+                    file_handle.seek(SeekFrom::Start(*start)).await?;
+                    let length = end - start + 1;
+                    let reader = BufReader::with_capacity(8192, file_handle.take(length));
+
+                    Ok(builder
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .insert_header(("Content-Type", content_type.as_ref()))
+                        .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, file_size)))
+                        .no_chunking(length)
+                        .streaming(metered(ReaderStream::new(reader), timer)))
+                }
+                _ => {
+                    let boundary = uuid::Uuid::new_v4().simple().to_string();
+                    let mut length = 0u64;
+                    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+
+                    for (start, end) in &ranges {
+                        let part_header = Bytes::from(format!(
+                            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                            boundary, content_type, start, end, file_size
+                        ));
+                        length += part_header.len() as u64 + (end - start + 1);
+                        parts.push(BodyPart::Bytes(part_header));
+                        parts.push(BodyPart::Range {
+                            start: *start,
+                            length: end - start + 1,
+                        });
+                    }
+                    let closing = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+                    length += closing.len() as u64;
+                    parts.push(BodyPart::Bytes(closing));
+
+                    Ok(builder
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .insert_header((
+                            "Content-Type",
+                            format!("multipart/byteranges; boundary={}", boundary),
+                        ))
+                        .no_chunking(length)
+                        .streaming(metered(multipart_body(file_handle, parts), timer)))
+                }
+            }
         }
         Err(e) => {
             log::error!("File retrieval error: {}", e);
@@ -85,3 +356,452 @@ pub async fn get_file(
         }
     }
 }
+
+/// Counts a download body's bytes as they are sent; its duration is recorded when the body is
+/// finished or dropped.
+fn metered<S, E>(body: S, timer: DownloadTimer) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    body.inspect(move |chunk| {
+        if let Ok(data) = chunk {
+            timer.sent(data.len());
+        }
+    })
+}
+
+/// Lists stored files a page at a time. Needs a device token.
+pub async fn list_files(
+    req: HttpRequest,
+    query: web::Query<FileListQuery>,
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+) -> Result<HttpResponse, Error> {
+    if let Err(response) = require_device(&req, &discovery_service, "Listing files") {
+        return Ok(response);
+    }
+
+    let files = file_service.list_files(&query);
+    Ok(HttpResponse::Ok().json(ApiResponse::new(0, "success", "Files retrieved", Some(files))))
+}
+
+/// Serves `GET` and `HEAD` for a file's metadata without touching its contents. Needs a device
+/// token.
+pub async fn get_file_meta(
+    req: HttpRequest,
+    file_id: web::Path<String>,
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+) -> Result<HttpResponse, Error> {
+    if let Err(response) = require_device(&req, &discovery_service, "File metadata") {
+        return Ok(response);
+    }
+
+    match file_service.get_file_info(&file_id) {
+        Ok(file) => {
+            let mut builder = HttpResponse::Ok();
+            if let Some(sha256) = &file.sha256 {
+                builder.insert_header(ETag(EntityTag::new_strong(sha256.clone())));
+            }
+            Ok(builder.json(ApiResponse::new(0, "success", "File metadata", Some(file))))
+        }
+        Err(e) => Ok(HttpResponse::NotFound().json(ApiResponse::<()>::new(
+            1,
+            "error",
+            &format!("File error: {}", e),
+            None,
+        ))),
+    }
+}
+
+/// Deletes a file. Only the device that uploaded it may; files uploaded without a token may be
+/// deleted by any paired device.
+pub async fn delete_file(
+    req: HttpRequest,
+    file_id: web::Path<String>,
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+) -> Result<HttpResponse, Error> {
+    let device_id = match require_device(&req, &discovery_service, "Deleting files") {
+        Ok(device_id) => device_id,
+        Err(response) => return Ok(response),
+    };
+
+    let result = file_service.get_file_info(&file_id).and_then(|file| match &file.owner {
+        Some(owner) if *owner != device_id => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Only the device that uploaded the file can delete it",
+        )),
+        _ => file_service.delete_file(&file_id),
+    });
+    match result {
+        Ok(file) => Ok(HttpResponse::Ok().json(ApiResponse::new(
+            0,
+            "success",
+            "File deleted",
+            Some(file),
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(HttpResponse::NotFound().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                &format!("File error: {}", e),
+                None,
+            )))
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Ok(HttpResponse::Forbidden().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                &e.to_string(),
+                None,
+            )))
+        }
+        Err(e) => {
+            log::error!("File deletion error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                &format!("Failed to delete file: {}", e),
+                None,
+            )))
+        }
+    }
+}
+
+enum RangeRequest {
+    Full,
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// Resolves the `Range` and `If-Range` headers into sorted, non-overlapping inclusive ranges.
+///
+/// Malformed or non-byte ranges are ignored per RFC 7233, as is a `Range` whose `If-Range`
+/// validator no longer matches the file.
+fn requested_ranges(
+    req: &HttpRequest,
+    file_size: u64,
+    last_modified: Option<SystemTime>,
+    etag: Option<&EntityTag>,
+) -> RangeRequest {
+    let Ok(Range::Bytes(specs)) = Range::parse(req) else {
+        return RangeRequest::Full;
+    };
+
+    if let Ok(if_range) = IfRange::parse(req) {
+        let matches = match if_range {
+            IfRange::Date(date) => last_modified
+                .map(|modified| HttpDate::from(modified) == date)
+                .unwrap_or(false),
+            IfRange::EntityTag(tag) => etag.map(|etag| etag.strong_eq(&tag)).unwrap_or(false),
+        };
+        if !matches {
+            return RangeRequest::Full;
+        }
+    }
+
+    let mut ranges: Vec<(u64, u64)> = specs
+        .iter()
+        .filter_map(|spec| spec.to_satisfiable_range(file_size))
+        .collect();
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    // Coalesce overlapping and adjacent ranges so clients cannot request the same bytes repeatedly
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    RangeRequest::Partial(merged)
+}
+
+/// Formats a hex SHA-256 as an RFC 3230 `Digest` header value.
+fn digest_header_value(sha256: &str) -> Option<String> {
+    let bytes = (0..sha256.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(sha256.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(format!("sha-256={}", BASE64.encode(bytes)))
+}
+
+/// Extracts the SHA-256 from an RFC 3230 `Digest` header as hex.
+fn parse_digest_header(value: &str) -> Option<String> {
+    let encoded = value.split(',').find_map(|digest| {
+        let (algorithm, encoded) = digest.trim().split_once('=')?;
+        algorithm.eq_ignore_ascii_case("sha-256").then_some(encoded)
+    })?;
+    let bytes = BASE64.decode(encoded).ok()?;
+    (bytes.len() == 32).then(|| bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// A piece of a `multipart/byteranges` body.
+enum BodyPart {
+    Bytes(Bytes),
+    Range { start: u64, length: u64 },
+}
+
+/// Streams a `multipart/byteranges` body, reading every range from the one handle opened for
+/// the request, so the parts are still served if the blob is unlinked meanwhile.
+fn multipart_body(file: TokioFile, parts: Vec<BodyPart>) -> impl Stream<Item = io::Result<Bytes>> {
+    let state = (file, VecDeque::from(parts), 0u64);
+    stream::try_unfold(state, |(mut file, mut parts, mut remaining)| async move {
+        loop {
+            if remaining > 0 {
+                let mut buf = vec![0; remaining.min(64 * 1024) as usize];
+                let read = file.read(&mut buf).await?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File ended inside a requested range"));
+                }
+                buf.truncate(read);
+                remaining -= read as u64;
+                return Ok(Some((Bytes::from(buf), (file, parts, remaining))));
+            }
+            match parts.pop_front() {
+                None => return Ok(None),
+                Some(BodyPart::Bytes(bytes)) => return Ok(Some((bytes, (file, parts, 0)))),
+                Some(BodyPart::Range { start, length }) => {
+                    file.seek(SeekFrom::Start(start)).await?;
+                    remaining = length;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    async fn stored_file(file_service: &FileService, content: &'static str) -> String {
+        stored_file_with(file_service, content, RetentionOptions::default()).await
+    }
+
+    async fn stored_file_with(file_service: &FileService, content: &'static str, retention: RetentionOptions) -> String {
+        let session = file_service
+            .create_upload_session("digits.txt", content.len() as u64, None, retention, None)
+            .await
+            .unwrap();
+        let body = stream::iter([Ok::<_, io::Error>(Bytes::from_static(content.as_bytes()))]);
+        file_service.write_upload_chunk(&session.id, 0, body).await.unwrap();
+        file_service.finalize_upload(&session.id).await.unwrap().id
+    }
+
+    #[actix_rt::test]
+    async fn test_get_file_range_requests() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_service = web::Data::new(FileService::new(temp_dir.path().to_path_buf()).unwrap());
+        let file_id = stored_file(&file_service, "0123456789").await;
+        let uri = format!("/api/files/{}", file_id);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .route("/api/files/{id}", web::get().to(get_file)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri(&uri).insert_header(("Range", "bytes=2-5")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 2-5/10");
+        assert_eq!(test::read_body(resp).await, "2345");
+
+        let req = test::TestRequest::get().uri(&uri).insert_header(("Range", "bytes=-3")).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "789");
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Range", "bytes=0-1,8-"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = resp.headers().get("Content-Type").unwrap().to_str().unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-1/10\r\n\r\n01\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+
+        let req = test::TestRequest::get().uri(&uri).insert_header(("Range", "bytes=20-")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes */10");
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Range", "bytes=2-5"))
+            .insert_header(("If-Range", "Wed, 21 Oct 2015 07:28:00 GMT"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "0123456789");
+
+        let etag = "\"84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882\"";
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Range", "bytes=2-5"))
+            .insert_header(("If-Range", etag))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get("ETag").unwrap(), etag);
+        assert_eq!(
+            resp.headers().get("Digest").unwrap(),
+            "sha-256=hNiYd/DUBB77a/kaFvAkjy/Vc+avBcGflr7bn4gveII="
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_multi_range_response_survives_the_last_allowed_download() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_service = web::Data::new(FileService::new(temp_dir.path().to_path_buf()).unwrap());
+        let retention = RetentionOptions {
+            ttl_secs: None,
+            max_downloads: Some(1),
+        };
+        let file_id = stored_file_with(&file_service, "0123456789", retention).await;
+        let uri = format!("/api/files/{}", file_id);
+        let blob = file_service.get_file_info(&file_id).unwrap().file_path;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .route("/api/files/{id}", web::get().to(get_file)),
+        )
+        .await;
+
+        // Counting the download unlinks the blob before any part of the body is read
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Range", "bytes=0-1,4-5,8-"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!blob.exists());
+        let length: usize = resp.headers().get("Content-Length").unwrap().to_str().unwrap().parse().unwrap();
+        let body = test::read_body(resp).await;
+        assert_eq!(body.len(), length);
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Content-Range: bytes 0-1/10\r\n\r\n01\r\n"));
+        assert!(body.contains("Content-Range: bytes 4-5/10\r\n\r\n45\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_list_meta_and_delete_need_a_token() {
+        use actix_web::http::header::AUTHORIZATION;
+        use crate::services::discovery_service::PairingStart;
+        use crate::storage::trust_store::TrustStore;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_service = web::Data::new(FileService::new(temp_dir.path().to_path_buf()).unwrap());
+        let discovery = Arc::new(DiscoveryService::new(TrustStore::in_memory()));
+        let owner_token = discovery.bootstrap("owner", &discovery.bootstrap_pin().unwrap()).unwrap();
+        let Ok(PairingStart::Pending { pairing_id, pin, .. }) = discovery.begin_pairing("other") else {
+            panic!("second device should need approval");
+        };
+        let (_, other_token) = discovery.approve_pairing(&pairing_id, &pin).unwrap();
+
+        let mut ids = Vec::new();
+        for (name, content, ttl_secs) in [
+            ("beta.txt", "b", None),
+            ("Alpha.txt", "aaa", None),
+            ("gamma.log", "cc", None),
+            ("stale.txt", "s", Some(1)),
+        ] {
+            let retention = RetentionOptions {
+                ttl_secs,
+                max_downloads: None,
+            };
+            let session = file_service
+                .create_upload_session(name, content.len() as u64, None, retention, Some("owner"))
+                .await
+                .unwrap();
+            let body = stream::iter([Ok::<_, io::Error>(Bytes::from_static(content.as_bytes()))]);
+            file_service.write_upload_chunk(&session.id, 0, body).await.unwrap();
+            ids.push(file_service.finalize_upload(&session.id).await.unwrap().id);
+        }
+        let stale = ids.pop().unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .app_data(web::Data::new(Arc::clone(&discovery)))
+                .route("/api/files", web::get().to(list_files))
+                .route("/api/files/{id}", web::delete().to(delete_file))
+                .route("/api/files/{id}/meta", web::get().to(get_file_meta))
+                .route("/api/files/{id}/meta", web::head().to(get_file_meta)),
+        )
+        .await;
+        let bearer = |token: &str| (AUTHORIZATION, format!("Bearer {}", token));
+        let list = |uri: &str| test::TestRequest::get().uri(uri).insert_header(bearer(&owner_token)).to_request();
+
+        for req in [
+            test::TestRequest::get().uri("/api/files").to_request(),
+            test::TestRequest::get().uri("/api/files").insert_header(bearer("forged")).to_request(),
+            test::TestRequest::get().uri(&format!("/api/files/{}/meta", ids[0])).to_request(),
+            test::TestRequest::delete().uri(&format!("/api/files/{}", ids[0])).to_request(),
+        ] {
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // The file past its TTL is left out before the reaper gets to it
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        let names = |body: &serde_json::Value| -> Vec<String> {
+            let files = body["data"]["files"].as_array().unwrap();
+            files.iter().map(|file| file["filename"].as_str().unwrap().to_string()).collect()
+        };
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, list("/api/files?sort=size&order=asc&per_page=2")).await;
+        assert_eq!(body["data"]["total"], 3);
+        assert_eq!(names(&body), ["beta.txt", "gamma.log"]);
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, list("/api/files?sort=size&order=asc&per_page=2&page=2")).await;
+        assert_eq!(names(&body), ["Alpha.txt"]);
+        assert_eq!(body["data"]["page"], 2);
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, list("/api/files?sort=filename&order=desc")).await;
+        assert_eq!(names(&body), ["gamma.log", "beta.txt", "Alpha.txt"]);
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, list("/api/files?filename=ALPHA")).await;
+        assert_eq!(body["data"]["total"], 1);
+        assert_eq!(body["data"]["files"][0]["id"], ids[1].as_str());
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri(&format!("/api/files/{}/meta", ids[0]))
+            .insert_header(bearer(&other_token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().contains_key("ETag"));
+        let req = list(&format!("/api/files/{}/meta", stale));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        let delete = |token: &str| {
+            test::TestRequest::delete()
+                .uri(&format!("/api/files/{}", ids[0]))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, delete(&other_token)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, delete(&owner_token)).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, delete(&owner_token)).await.status(), StatusCode::NOT_FOUND);
+        let req = list(&format!("/api/files/{}/meta", ids[0]));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+}
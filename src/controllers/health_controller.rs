@@ -0,0 +1,155 @@
+use actix_web::{web, Error, HttpResponse, Result};
+use std::sync::Arc;
+
+use crate::models::response::ApiResponse;
+use crate::services::discovery_service::DiscoveryService;
+use crate::services::file_service::FileService;
+use crate::services::metrics_service::{Gauge, CONTENT_TYPE};
+use crate::websocket::registry::SessionRegistry;
+
+fn unavailable(message: &str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(ApiResponse::<()>::new(1, "error", message, None))
+}
+
+/// Liveness: answers as long as the server can handle requests at all.
+pub async fn healthz() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ApiResponse::<()>::new(0, "success", "OK", None)))
+}
+
+/// Readiness: fails while shutting down or when files can no longer be stored.
+pub async fn readyz(file_service: web::Data<FileService>) -> Result<HttpResponse, Error> {
+    if file_service.is_draining() {
+        return Ok(unavailable("Server is shutting down"));
+    }
+    if let Err(e) = file_service.check_writable() {
+        log::error!("Readiness check failed: storage is not writable: {}", e);
+        return Ok(unavailable(&format!("Storage is not writable: {}", e)));
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse::<()>::new(0, "success", "Ready", None)))
+}
+
+pub async fn metrics(
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+    sessions: web::Data<Arc<SessionRegistry>>,
+) -> Result<HttpResponse, Error> {
+    let (devices, trusted_devices) = discovery_service.device_counts();
+    let usage = file_service.usage();
+    let gauges = [
+        Gauge {
+            name: "windrop_websocket_sessions",
+            help: "Open WebSocket sessions",
+            value: sessions.len() as f64,
+        },
+        Gauge {
+            name: "windrop_registered_devices",
+            help: "Devices registered with discovery, including ones waiting to reconnect",
+            value: devices as f64,
+        },
+        Gauge {
+            name: "windrop_trusted_devices",
+            help: "Registered devices that are trusted",
+            value: trusted_devices as f64,
+        },
+        Gauge {
+            name: "windrop_stored_files",
+            help: "Files currently stored",
+            value: usage.file_count as f64,
+        },
+        Gauge {
+            name: "windrop_stored_bytes",
+            help: "Bytes used by stored files",
+            value: usage.used_bytes as f64,
+        },
+        Gauge {
+            name: "windrop_reserved_bytes",
+            help: "Bytes reserved by unfinished upload sessions",
+            value: usage.reserved_bytes as f64,
+        },
+    ];
+
+    Ok(HttpResponse::Ok()
+        .content_type(CONTENT_TYPE)
+        .body(file_service.metrics().render(&gauges)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use crate::controllers::file_controller::get_file;
+    use crate::controllers::upload_controller::{create_upload, finalize_upload, upload_chunk};
+    use crate::storage::trust_store::TrustStore;
+
+    #[actix_rt::test]
+    async fn test_health_readiness_and_metrics() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_service = web::Data::new(FileService::new(temp_dir.path().to_path_buf()).unwrap());
+        let discovery_service = Arc::new(DiscoveryService::new(TrustStore::in_memory()));
+        discovery_service.register_device("phone".to_string(), "Phone".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .app_data(web::Data::new(discovery_service))
+                .app_data(web::Data::new(Arc::new(SessionRegistry::new())))
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz))
+                .route("/metrics", web::get().to(metrics))
+                .service(
+                    web::scope("/api")
+                        .route("/uploads", web::post().to(create_upload))
+                        .route("/uploads/{id}", web::put().to(upload_chunk))
+                        .route("/uploads/{id}/finalize", web::post().to(finalize_upload))
+                        .route("/files/{id}", web::get().to(get_file)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/api/uploads")
+            .set_json(serde_json::json!({ "filename": "hello.txt", "size": 11 }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let upload_id = body["data"]["id"].as_str().unwrap().to_string();
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/uploads/{}?offset=0", upload_id))
+            .set_payload("hello world")
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/uploads/{}/finalize", upload_id))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let file_id = body["data"]["id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get().uri(&format!("/api/files/{}", file_id)).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "hello world");
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), CONTENT_TYPE);
+        let text = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        for line in [
+            "windrop_uploads_total 1",
+            "windrop_received_bytes_total 11",
+            "windrop_downloads_total 1",
+            "windrop_download_duration_seconds_count 1",
+            "windrop_sent_bytes_total 11",
+            "windrop_registered_devices 1",
+            "windrop_websocket_sessions 0",
+            "windrop_stored_bytes 11",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+
+        file_service.begin_shutdown();
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -1,2 +1,7 @@
+pub mod auth;
 pub mod file_controller;
-pub mod websocket_controller;
\ No newline at end of file
+pub mod health_controller;
+pub mod tls_controller;
+pub mod upload_controller;
+pub mod usage_controller;
+pub mod websocket_controller;
@@ -0,0 +1,13 @@
+use actix_web::{web, Error, HttpResponse, Result};
+
+use crate::models::response::ApiResponse;
+use crate::models::tls::TlsInfo;
+
+pub async fn get_tls_info(info: web::Data<TlsInfo>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ApiResponse::new(
+        0,
+        "success",
+        "TLS status",
+        Some(info.get_ref().clone()),
+    )))
+}
@@ -0,0 +1,238 @@
+use actix_web::{web, HttpRequest, HttpResponse, Error, Result};
+use serde::Deserialize;
+use std::io;
+use std::sync::Arc;
+use crate::controllers::auth::request_device;
+use crate::services::discovery_service::DiscoveryService;
+use crate::services::file_service::FileService;
+use crate::models::response::ApiResponse;
+use crate::models::upload::{CreateUploadRequest, UploadSession};
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkOffset {
+    offset: u64,
+}
+
+fn error_response(e: io::Error) -> HttpResponse {
+    let body = ApiResponse::<()>::new(1, "error", &e.to_string(), None);
+    match e.kind() {
+        io::ErrorKind::NotFound => HttpResponse::NotFound().json(body),
+        io::ErrorKind::InvalidInput => HttpResponse::BadRequest().json(body),
+        io::ErrorKind::InvalidData => HttpResponse::UnprocessableEntity().json(body),
+        io::ErrorKind::FileTooLarge | io::ErrorKind::QuotaExceeded => HttpResponse::PayloadTooLarge().json(body),
+        io::ErrorKind::WouldBlock => HttpResponse::Conflict().json(body),
+        io::ErrorKind::ResourceBusy => HttpResponse::ServiceUnavailable().json(body),
+        _ => {
+            log::error!("Upload session error: {}", e);
+            HttpResponse::InternalServerError().json(body)
+        }
+    }
+}
+
+/// Session status, with the contiguous offset and total length mirrored in headers named after
+/// tus. The endpoints are only tus-like; clients that speak tus proper are not supported.
+fn session_response(mut builder: actix_web::HttpResponseBuilder, session: UploadSession, message: &str) -> HttpResponse {
+    builder
+        .insert_header(("Upload-Offset", session.offset().to_string()))
+        .insert_header(("Upload-Length", session.size.to_string()))
+        .json(ApiResponse::new(0, "success", message, Some(session)))
+}
+
+pub async fn create_upload(
+    req: HttpRequest,
+    request: web::Json<CreateUploadRequest>,
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+) -> Result<HttpResponse, Error> {
+    let owner = match request_device(&req, &discovery_service) {
+        Ok(owner) => owner,
+        Err(response) => return Ok(response),
+    };
+
+    let session = file_service
+        .create_upload_session(
+            &request.filename,
+            request.size,
+            request.sha256.as_deref(),
+            request.retention.clone(),
+            owner.as_deref(),
+        )
+        .await;
+
+    match session {
+        Ok(session) => {
+            let mut builder = HttpResponse::Created();
+            builder.insert_header(("Location", format!("/api/uploads/{}", session.id)));
+            Ok(session_response(builder, session, "Upload session created"))
+        }
+        Err(e) => Ok(error_response(e)),
+    }
+}
+
+pub async fn get_upload(
+    upload_id: web::Path<String>,
+    file_service: web::Data<FileService>,
+) -> Result<HttpResponse, Error> {
+    match file_service.get_upload_session(&upload_id) {
+        Ok(session) => Ok(session_response(HttpResponse::Ok(), session, "Upload session status")),
+        Err(e) => Ok(error_response(e)),
+    }
+}
+
+pub async fn upload_chunk(
+    upload_id: web::Path<String>,
+    query: web::Query<ChunkOffset>,
+    payload: web::Payload,
+    file_service: web::Data<FileService>,
+) -> Result<HttpResponse, Error> {
+    match file_service.write_upload_chunk(&upload_id, query.offset, payload).await {
+        Ok(session) => Ok(session_response(HttpResponse::Ok(), session, "Chunk received")),
+        Err(e) => Ok(error_response(e)),
+    }
+}
+
+pub async fn finalize_upload(
+    upload_id: web::Path<String>,
+    file_service: web::Data<FileService>,
+) -> Result<HttpResponse, Error> {
+    match file_service.finalize_upload(&upload_id).await {
+        Ok(file) => Ok(HttpResponse::Created().json(ApiResponse::new(
+            0,
+            "success",
+            "File uploaded successfully",
+            Some(file),
+        ))),
+        Err(e) => Ok(error_response(e)),
+    }
+}
+
+pub async fn cancel_upload(
+    upload_id: web::Path<String>,
+    file_service: web::Data<FileService>,
+) -> Result<HttpResponse, Error> {
+    match file_service.cancel_upload(&upload_id).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(ApiResponse::<()>::new(
+            0,
+            "success",
+            "Upload session cancelled",
+            None,
+        ))),
+        Err(e) => Ok(error_response(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use crate::controllers::file_controller::get_file;
+    use crate::storage::trust_store::TrustStore;
+
+    fn discovery_service() -> web::Data<Arc<DiscoveryService>> {
+        web::Data::new(Arc::new(DiscoveryService::new(TrustStore::in_memory())))
+    }
+
+    #[actix_rt::test]
+    async fn test_resumable_upload_out_of_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_service = web::Data::new(FileService::new(temp_dir.path().to_path_buf()).unwrap());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .app_data(discovery_service())
+                .service(
+                    web::scope("/api")
+                        .route("/uploads", web::post().to(create_upload))
+                        .route("/uploads/{id}", web::get().to(get_upload))
+                        .route("/uploads/{id}", web::put().to(upload_chunk))
+                        .route("/uploads/{id}/finalize", web::post().to(finalize_upload))
+                        .route("/files/{id}", web::get().to(get_file)),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/uploads")
+            .set_json(serde_json::json!({ "filename": "hello.txt", "size": 11 }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let upload_id = body["data"]["id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/uploads/{}?offset=6", upload_id))
+            .set_payload("world")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("Upload-Offset").unwrap(), "0");
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/uploads/{}/finalize", upload_id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/uploads/{}?offset=0", upload_id))
+            .set_payload("hello ")
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["received"], serde_json::json!([{ "start": 0, "end": 11 }]));
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/uploads/{}/finalize", upload_id))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let file_id = body["data"]["id"].as_str().unwrap();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/files/{}", file_id))
+            .to_request();
+        let downloaded = test::call_and_read_body(&app, req).await;
+        assert_eq!(downloaded, "hello world");
+    }
+
+    #[actix_rt::test]
+    async fn test_finalize_rejects_digest_mismatch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_service = web::Data::new(FileService::new(temp_dir.path().to_path_buf()).unwrap());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .app_data(discovery_service())
+                .service(
+                    web::scope("/api")
+                        .route("/uploads", web::post().to(create_upload))
+                        .route("/uploads/{id}", web::put().to(upload_chunk))
+                        .route("/uploads/{id}/finalize", web::post().to(finalize_upload)),
+                ),
+        )
+        .await;
+
+        // Digest of "hello world", but the uploaded bytes differ
+        let req = test::TestRequest::post()
+            .uri("/api/uploads")
+            .set_json(serde_json::json!({
+                "filename": "hello.txt",
+                "size": 11,
+                "sha256": "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9",
+            }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let upload_id = body["data"]["id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/uploads/{}?offset=0", upload_id))
+            .set_payload("hello w0rld")
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/uploads/{}/finalize", upload_id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(file_service.get_upload_session(&upload_id).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
+use std::sync::Arc;
+
+use crate::controllers::auth::require_device;
+use crate::models::response::ApiResponse;
+use crate::services::discovery_service::DiscoveryService;
+use crate::services::file_service::FileService;
+
+/// Storage totals and the calling device's own usage. Needs a device token.
+pub async fn get_usage(
+    req: HttpRequest,
+    file_service: web::Data<FileService>,
+    discovery_service: web::Data<Arc<DiscoveryService>>,
+) -> Result<HttpResponse, Error> {
+    let device_id = match require_device(&req, &discovery_service, "Usage") {
+        Ok(device_id) => device_id,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(HttpResponse::Ok().json(ApiResponse::new(
+        0,
+        "success",
+        "Storage usage",
+        Some(file_service.usage().report_for(&device_id)),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::{http::StatusCode, test, App};
+    use crate::controllers::file_controller::upload_file;
+    use crate::controllers::upload_controller::create_upload;
+    use crate::models::usage::QuotaLimits;
+    use crate::storage::trust_store::TrustStore;
+    use crate::websocket::registry::SessionRegistry;
+    use crate::websocket::transfers::TransferManager;
+    use std::time::Duration;
+
+    fn multipart_upload(contents: &str, token: Option<&str>) -> test::TestRequest {
+        let body = format!(
+            "--BOUNDARY\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\n{}\r\n--BOUNDARY--\r\n",
+            contents
+        );
+        let mut req = test::TestRequest::post()
+            .uri("/api/upload")
+            .insert_header(("Content-Type", "multipart/form-data; boundary=BOUNDARY"))
+            .set_payload(body);
+        if let Some(token) = token {
+            req = req.insert_header((AUTHORIZATION, format!("Bearer {}", token)));
+        }
+        req
+    }
+
+    #[actix_rt::test]
+    async fn test_quotas_reject_uploads_with_413() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let limits = QuotaLimits {
+            max_file_size: Some(1024),
+            global_quota: Some(1000),
+            device_quota: Some(10),
+        };
+        let file_service = web::Data::new(
+            FileService::new(temp_dir.path().to_path_buf()).unwrap().with_limits(limits),
+        );
+        let discovery_service = Arc::new(DiscoveryService::new(TrustStore::in_memory()));
+        discovery_service.register_device("laptop".to_string(), "laptop".to_string());
+        let pin = discovery_service.bootstrap_pin().unwrap();
+        let token = discovery_service.bootstrap("laptop", &pin).unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(file_service.clone())
+                .app_data(web::Data::new(discovery_service))
+                .app_data(web::Data::new(Arc::new(TransferManager::new(
+                    Arc::new(SessionRegistry::new()),
+                    Duration::from_secs(30),
+                ))))
+                .service(
+                    web::scope("/api")
+                        .route("/upload", web::post().to(upload_file))
+                        .route("/uploads", web::post().to(create_upload))
+                        .route("/usage", web::get().to(get_usage)),
+                ),
+        )
+        .await;
+
+        let resp = test::call_service(&app, multipart_upload("12345678", Some(&token)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // The device has 2 bytes left; anonymous uploads have their own allowance
+        let resp = test::call_service(&app, multipart_upload("12345678", Some(&token)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = test::call_service(&app, multipart_upload("12345678", None).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = test::call_service(&app, multipart_upload("1", Some("forged")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // A declared length is checked before the body is read
+        let req = multipart_upload("1", None).insert_header(("Upload-Length", "2048")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let req = test::TestRequest::post()
+            .uri("/api/uploads")
+            .set_json(serde_json::json!({ "filename": "big.bin", "size": 4096 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "error");
+
+        // Usage is only shown to paired devices, and only their own share of it
+        let req = test::TestRequest::get().uri("/api/usage").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get()
+            .uri("/api/usage")
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["used_bytes"], 16);
+        assert_eq!(body["data"]["file_count"], 2);
+        assert_eq!(body["data"]["limits"]["device_quota"], 10);
+        assert_eq!(body["data"]["device"]["device_id"], "laptop");
+        assert_eq!(body["data"]["device"]["used_bytes"], 8);
+        assert!(body["data"].get("devices").is_none());
+    }
+}
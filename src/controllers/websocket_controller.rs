@@ -1,25 +1,162 @@
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use serde::Deserialize;
+use utoipa::{IntoParams, TupleUnit};
 use std::sync::Arc;
 
+use crate::config::WebSocketConfig;
+use crate::middleware::logger::{RequestDevice, RequestId};
+use crate::models::response::ApiResponse;
 use crate::services::discovery_service::DiscoveryService;
 use crate::websocket::connection::FileTransferWs;
+use crate::websocket::message::FileTransferMessage;
+use crate::websocket::registry::SessionRegistry;
+use crate::websocket::transfers::TransferManager;
 
-#[derive(Debug, Deserialize)]
+/// Subprotocol a client offers to say it speaks Windrop; the server answers with it
+const WS_PROTOCOL: &str = "windrop";
+/// Prefix of the subprotocol that carries a pairing token, for browsers, which cannot set headers
+const TOKEN_PROTOCOL_PREFIX: &str = "windrop-token.";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct DeviceName {
+    /// Name shown to other devices
     name: String,
+    /// No longer accepted, as query strings end up in logs; refused with a 400
+    #[param(ignore)]
+    token: Option<String>,
+    /// Id the presented token or resume token must belong to; refused without one
+    device_id: Option<String>,
+}
+
+fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized().json(ApiResponse::<()>::new(1, "error", message, None))
+}
+
+/// The pairing or resume token from `Authorization: Bearer <token>`, or from a
+/// `windrop-token.<token>` subprotocol in `Sec-WebSocket-Protocol`.
+fn request_token(req: &HttpRequest) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let protocol = || {
+        req.headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|protocol| protocol.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX))
+    };
+    bearer.or_else(protocol).map(|token| token.trim().to_string())
 }
 
+/// Opens the device WebSocket.
+///
+/// Messages in both directions are JSON `FileTransferMessage` objects in text frames, except
+/// file chunks, which may also be sent as binary chunk frames. An unpaired device is sent
+/// `PairingRequired` and must be approved by a trusted device before it can transfer files.
+///
+/// Paired devices present their token as `Authorization: Bearer <token>`. An unpaired device
+/// reconnects under its id by presenting the `resume_token` from its last `PairingRequired` or
+/// `BootstrapRequired` the same way. Browsers, which cannot set that header, offer the
+/// subprotocols `windrop` and `windrop-token.<token>` instead; the server answers with `windrop`.
+#[utoipa::path(
+    get,
+    path = "/api/ws",
+    tag = "websocket",
+    params(
+        DeviceName,
+        ("Sec-WebSocket-Protocol" = Option<String>, Header, description = "`windrop, windrop-token.<token>` for clients that cannot send `Authorization`"),
+    ),
+    responses(
+        (status = 101, description = "Switched to the WebSocket protocol; frames carry `FileTransferMessage`s", body = FileTransferMessage),
+        (status = 400, description = "`device_id` is not a UUID, or a token was sent in the query string", body = ApiResponse<TupleUnit>),
+        (status = 401, description = "Unknown token, or a `device_id` given without the token or resume token issued for it", body = ApiResponse<TupleUnit>),
+        (status = 503, description = "Server is shutting down", body = ApiResponse<TupleUnit>),
+    ),
+    security((), ("device_token" = [])),
+)]
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_route(
     req: HttpRequest,
     stream: web::Payload,
     device_name: web::Query<DeviceName>,
     discovery_service: web::Data<Arc<DiscoveryService>>,
+    sessions: web::Data<Arc<SessionRegistry>>,
+    transfers: web::Data<Arc<TransferManager>>,
+    timing: web::Data<WebSocketConfig>,
+    request_id: RequestId,
 ) -> Result<HttpResponse, Error> {
+    if sessions.is_closing() {
+        return Ok(HttpResponse::ServiceUnavailable().json(ApiResponse::<()>::new(
+            1,
+            "error",
+            "Server is shutting down",
+            None,
+        )));
+    }
+
+    if device_name.token.is_some() {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<()>::new(
+            1,
+            "error",
+            "Send the token as Authorization: Bearer or a windrop-token subprotocol, not in the query string",
+            None,
+        )));
+    }
+
+    if let Some(device_id) = &device_name.device_id {
+        if uuid::Uuid::parse_str(device_id).is_err() {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                "device_id must be a UUID",
+                None,
+            )));
+        }
+    }
+
+    // A paired device is known by its token, an unpaired one by the resume token it was last
+    // issued, so an id can only be taken over by whoever holds its secret
+    let device_id = match request_token(&req) {
+        Some(token) => {
+            let Some(device_id) = discovery_service
+                .authenticate(&token)
+                .or_else(|| discovery_service.resume(&token))
+            else {
+                log::warn!("Rejected WebSocket connection with an unknown device token");
+                return Ok(unauthorized("Unknown device token"));
+            };
+            if device_name.device_id.as_ref().is_some_and(|claimed| *claimed != device_id) {
+                log::warn!("Rejected WebSocket connection claiming device {} with another device's token", device_id);
+                return Ok(unauthorized("Token was issued to another device"));
+            }
+            Some(device_id)
+        }
+        None => {
+            if let Some(device_id) = &device_name.device_id {
+                log::warn!("Rejected WebSocket connection claiming device {} without a token", device_id);
+                return Ok(unauthorized("A token or resume token is required for this device"));
+            }
+            None
+        }
+    };
+
     let ws = FileTransferWs::new(
         device_name.name.clone(),
+        device_id,
         Arc::clone(&discovery_service),
+        Arc::clone(&sessions),
+        Arc::clone(&transfers),
+        **timing,
+        request_id,
     );
-    ws::start(ws, &req, stream)
+    req.extensions_mut().insert(RequestDevice(ws.device_id().to_string()));
+    ws::WsResponseBuilder::new(ws, &req, stream)
+        .protocols(&[WS_PROTOCOL])
+        .frame_size(timing.max_frame_size)
+        .start()
 }
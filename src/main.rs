@@ -1,113 +1,276 @@
+mod config;
 mod controllers;
 mod middleware;
 mod models;
+mod net;
+mod openapi;
+mod proxy_protocol;
 mod repositories;
 mod services;
 mod storage;
+mod tls;
 mod websocket;
 
-use crate::middleware::{logger::RequestLogger, rate_limit::RateLimiter};
-use actix_cors::Cors;
-use actix_web::middleware::Logger as ActixLogger;
+use crate::config::{Cli, Config};
+use crate::middleware::access_control::{self, AccessControl};
+use crate::middleware::{client_ip::ClientIpResolver, logger::RequestLogger, rate_limit::RateLimiter};
+use actix_web::dev::ServerHandle;
 use actix_web::{web, App, HttpServer};
-use controllers::file_controller::{get_file, upload_file};
+use controllers::file_controller::{delete_file, get_file, get_file_meta, list_files, upload_file};
+use controllers::health_controller::{healthz, metrics, readyz};
+use controllers::tls_controller::get_tls_info;
+use controllers::upload_controller::{
+    cancel_upload, create_upload, finalize_upload, get_upload, upload_chunk,
+};
+use controllers::usage_controller::get_usage;
 use controllers::websocket_controller::websocket_route;
+use proxy_protocol::ProxiedConnections;
 use services::discovery_service::DiscoveryService;
 use services::file_service::FileService;
+use services::mdns_service::{self, Advertisement, MdnsAdvertiser};
+use services::metrics_service::Metrics;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
-use std::{error::Error, net::Ipv4Addr};
+use storage::trust_store::TrustStore;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
+use websocket::registry::SessionRegistry;
+use websocket::transfers::TransferManager;
+
+/// JSON bodies are small control requests; file contents only arrive as streamed payloads
+const JSON_BODY_LIMIT: usize = 64 * 1024;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(middleware::logger::format_record)
+        .init();
+
+    let cli = <Cli as clap::Parser>::parse();
+    if let Some(secs) = cli.browse {
+        for server in mdns_service::browse(std::time::Duration::from_secs(secs))? {
+            println!("{}", serde_json::to_string(&server)?);
+        }
+        return Ok(());
+    }
 
-    // Initialize storage path
-    let storage_path = std::env::current_dir()?.join("file_storage");
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Invalid configuration: {}", e);
+            return Err(e);
+        }
+    };
+    let bind_addr = config.bind_addr()?;
+    let tls_identity = tls::load_identity(&config.tls)?;
 
-    let file_service = web::Data::new(FileService::new(storage_path)?);
-    let discovery_service = Arc::new(DiscoveryService::new());
+    let metrics_registry = Arc::new(Metrics::new());
+    let file_service = web::Data::new(
+        FileService::from_config(&config.storage)?.with_metrics(Arc::clone(&metrics_registry)),
+    );
+    file_service
+        .clone()
+        .into_inner()
+        .start_reaper(config.storage.reaper_interval());
+    let trust_store = TrustStore::open(config.storage.path.join("trusted_devices.json"))?;
+    let sessions = Arc::new(SessionRegistry::new());
+    let discovery_service = Arc::new(
+        DiscoveryService::new(trust_store).with_sessions(Arc::clone(&sessions)),
+    );
+    discovery_service.start_pruning(config.websocket.discovery_interval());
+    if let Some(pin) = discovery_service.bootstrap_pin() {
+        log::warn!("No device is paired yet. Pair the first device with setup PIN {}", pin);
+    }
+    let transfers = Arc::new(TransferManager::new(
+        Arc::clone(&sessions),
+        config.websocket.transfer_request_timeout(),
+    ));
+    transfers.start_expiry();
 
-    // let discovery_service_data = discovery_service.clone();
+    let rate_limiter = RateLimiter::from_config(&config.rate_limit).with_metrics(metrics_registry);
+    rate_limiter.start_eviction();
+    let websocket_config = web::Data::new(config.websocket);
 
-    // Create FileService instance with proper error handling
-    // let file_service = match FileService::new(storage_path) {
-    //     Ok(service) => {
-    //         log::info!("FileService initialized successfully");
-    //         web::Data::new(service)
-    //     },
-    //     Err(e) => {
-    //         log::error!("Failed to initialize FileService: {}", e);
-    //         return Err(std::io::Error::new(
-    //             std::io::ErrorKind::Other,
-    //             "Failed to initialize FileService"
-    //         ));
-    //     }
-    // };
+    let access = AccessControl::from_config(&config.access);
+    let access_config = config.access.clone();
 
-    let rate_limiter = RateLimiter::new(100, Duration::from_secs(60));
+    let proxied = config.proxy.proxy_protocol.then(ProxiedConnections::default);
+    let client_ip_resolver = match &proxied {
+        Some(proxied) => ClientIpResolver::new(config.proxy.trusted.clone()).with_proxy_protocol(proxied.clone()),
+        None => ClientIpResolver::new(config.proxy.trusted.clone()),
+    };
 
-    log::info!("Starting HTTP server at http://127.0.0.1:8080");
+    let tls_info = web::Data::new(
+        tls_identity
+            .as_ref()
+            .map(|identity| identity.info.clone())
+            .unwrap_or_default(),
+    );
+    let tls_fingerprint = tls_info.fingerprint_sha256.clone();
+    let api_doc = openapi::ApiDoc::openapi();
+    let shutdown_files = file_service.clone();
+    let shutdown_sessions = Arc::clone(&sessions);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(ActixLogger::default())
-            .wrap(RequestLogger)
             .wrap(rate_limiter.clone())
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header()
-                    .supports_credentials()
-                    .max_age(3600),
-            )
-            // .wrap(Cors::permissive())
-            .app_data(web::JsonConfig::default().limit(usize::MAX))
-            .app_data(web::PayloadConfig::default().limit(usize::MAX))
+            .wrap(access_control::cors(&access_config))
+            // Refused clients never use up rate limit allowances
+            .wrap(access.clone())
+            // Outside the rest, so refused, rate-limited and CORS-rejected requests are logged too
+            .wrap(RequestLogger)
+            // Before everything else, which all needs the client address
+            .wrap(client_ip_resolver.clone())
+            .app_data(web::JsonConfig::default().limit(JSON_BODY_LIMIT))
             .app_data(file_service.clone())
             .app_data(web::Data::new(Arc::clone(&discovery_service)))
-            // .timeout(std::time::Duration::from_secs(300))
+            .app_data(web::Data::new(Arc::clone(&sessions)))
+            .app_data(web::Data::new(Arc::clone(&transfers)))
+            .app_data(websocket_config.clone())
+            .app_data(tls_info.clone())
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+            .route("/metrics", web::get().to(metrics))
+            .service(SwaggerUi::new("/swagger-ui/{_:.*}").url(openapi::SPEC_PATH, api_doc.clone()))
             .service(
                 web::scope("/api")
                     .route("/upload", web::post().to(upload_file))
+                    .route("/files", web::get().to(list_files))
                     .route("/files/{id}", web::get().to(get_file))
+                    .route("/files/{id}", web::delete().to(delete_file))
+                    .route("/files/{id}/meta", web::get().to(get_file_meta))
+                    .route("/files/{id}/meta", web::head().to(get_file_meta))
+                    .route("/uploads", web::post().to(create_upload))
+                    .route("/uploads/{id}", web::get().to(get_upload))
+                    .route("/uploads/{id}", web::put().to(upload_chunk))
+                    .route("/uploads/{id}", web::delete().to(cancel_upload))
+                    .route("/uploads/{id}/finalize", web::post().to(finalize_upload))
+                    .route("/usage", web::get().to(get_usage))
+                    .route("/tls", web::get().to(get_tls_info))
                     .route("/ws", web::get().to(websocket_route)),
             )
     })
-    .workers(4)
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .workers(config.server.workers)
+    // Signals are handled below so transfers can drain before the workers stop
+    .disable_signals();
+
+    // With the PROXY protocol, proxies connect to a listener that strips the header and
+    // forwards to the server, which then only listens on loopback
+    let listen_addr = match proxied {
+        Some(_) => SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        None => bind_addr,
+    };
+    let server = match tls_identity {
+        Some(identity) => {
+            log::info!("Starting HTTPS server at https://{}", bind_addr);
+            if let Some(fingerprint) = &identity.info.fingerprint_sha256 {
+                log::info!("Certificate SHA-256 fingerprint: {}", fingerprint);
+            }
+            server.bind_rustls_0_23(listen_addr, identity.server_config)?
+        }
+        None => {
+            log::info!("Starting HTTP server at http://{}", bind_addr);
+            server.bind(listen_addr)?
+        }
+    };
+    if let Some(proxied) = proxied {
+        let listener = tokio::net::TcpListener::bind(bind_addr).await?;
+        log::info!("Expecting PROXY protocol headers on connections to {}", bind_addr);
+        actix_rt::spawn(proxy_protocol::serve(listener, server.addrs()[0], config.proxy.trusted.clone(), proxied));
+    }
+
+    let advertiser = if config.mdns.enabled && bind_addr.ip().is_loopback() {
+        log::warn!(
+            "Not advertising on the local network: {} is a loopback address. Bind to 0.0.0.0 or a LAN address to be discoverable",
+            bind_addr.ip()
+        );
+        None
+    } else if config.mdns.enabled {
+        let advertisement = Advertisement {
+            name: &config.server.name,
+            addr: bind_addr,
+            tls_fingerprint: tls_fingerprint.as_deref(),
+        };
+        match MdnsAdvertiser::start(&advertisement) {
+            Ok(advertiser) => Some(advertiser),
+            Err(e) => {
+                log::warn!("Serving without LAN advertisement: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let server = server.run();
+    actix_rt::spawn(shutdown_on_signal(
+        server.handle(),
+        shutdown_files.clone(),
+        shutdown_sessions,
+        advertiser,
+        config.server.shutdown_grace(),
+    ));
+    let result = server.await;
+
+    match shutdown_files.remove_partial_files() {
+        Ok((0, 0)) => {}
+        Ok((removed, 0)) => log::info!("Removed {} partial upload file(s)", removed),
+        Ok((removed, failed)) => log::warn!(
+            "Removed {} partial upload file(s); {} could not be removed",
+            removed,
+            failed
+        ),
+        Err(e) => log::warn!("Failed to remove partial uploads: {}", e),
+    }
+    result
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[actix_rt::test]
-    async fn test_server_startup() {
-        let storage_path = tempfile::tempdir().unwrap().path().to_path_buf();
-        let file_service = web::Data::new(FileService::new(storage_path).unwrap());
-        let discovery_service = Arc::new(DiscoveryService::new());
-
-        let server = HttpServer::new(move || {
-            App::new()
-                .app_data(file_service.clone())
-                .app_data(web::Data::new(Arc::clone(&discovery_service)))
-                .service(
-                    web::scope("/api")
-                        .route("/upload", web::post().to(upload_file))
-                        .route("/files/{id}", web::get().to(get_file))
-                        .route("/ws", web::get().to(websocket_route)),
-                )
-        })
-        .bind("127.0.0.1:0")
-        .unwrap();
-
-        let _ = server.run();
-        assert!(true); // Server started successfully
+/// Waits for SIGTERM or Ctrl-C, then stops taking new uploads and sessions, warns connected
+/// devices, gives running uploads `grace` to finish, and stops the server.
+async fn shutdown_on_signal(
+    server: ServerHandle,
+    file_service: web::Data<FileService>,
+    sessions: Arc<SessionRegistry>,
+    advertiser: Option<MdnsAdvertiser>,
+    grace: Duration,
+) {
+    wait_for_signal().await;
+    log::info!("Shutting down, giving uploads in progress {}s to finish", grace.as_secs());
+
+    if let Some(advertiser) = advertiser {
+        advertiser.stop();
+    }
+    file_service.begin_shutdown();
+    sessions.begin_shutdown(grace);
+
+    if file_service.drain(grace).await {
+        log::info!("All uploads finished");
     }
+    sessions.close_all(Duration::from_secs(1)).await;
+    server.stop(false).await;
 }
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,207 @@
+use actix_cors::Cors;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
+    body::{BoxBody, MessageBody},
+};
+use futures::future::{ok, Ready, LocalBoxFuture};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use crate::config::AccessConfig;
+use crate::models::response::ApiResponse;
+use crate::net::{private_networks, Cidr};
+use super::client_ip::client_ip;
+
+/// Refuses clients outside the allow list or inside the deny list with `403 Forbidden`. Applies
+/// to every route, WebSocket upgrades included.
+#[derive(Clone)]
+pub struct AccessControl {
+    /// Everyone is allowed when empty
+    allow: Arc<Vec<Cidr>>,
+    deny: Arc<Vec<Cidr>>,
+}
+
+impl AccessControl {
+    pub fn from_config(config: &AccessConfig) -> Self {
+        let mut allow = config.allow.clone();
+        if config.private_networks_only {
+            allow.extend(private_networks());
+        }
+        AccessControl {
+            allow: Arc::new(allow),
+            deny: Arc::new(config.deny.clone()),
+        }
+    }
+
+    /// Requests whose client address is unknown are only let through when nothing is restricted.
+    fn permits(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => {
+                (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+                    && !self.deny.iter().any(|cidr| cidr.contains(ip))
+            }
+            None => self.allow.is_empty() && self.deny.is_empty(),
+        }
+    }
+}
+
+/// CORS policy for the configured origins. `"*"` allows any origin, but without credentials,
+/// so a page elsewhere cannot act with a browser's cookies or client certificate. Other origins
+/// get no CORS headers, leaving the browser to keep them out; pages the server itself serves,
+/// such as the API docs, still work.
+pub fn cors(config: &AccessConfig) -> Cors {
+    let cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .max_age(3600)
+        .block_on_origin_mismatch(false);
+    if config.cors_origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+    config
+        .cors_origins
+        .iter()
+        .fold(cors, |cors, origin| cors.allowed_origin(origin))
+        .supports_credentials()
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessControl
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = AccessControlMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessControlMiddleware {
+            service,
+            access: self.clone(),
+        })
+    }
+}
+
+pub struct AccessControlMiddleware<S> {
+    service: S,
+    access: AccessControl,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessControlMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let client_ip = client_ip(&req);
+        if !self.access.permits(client_ip) {
+            let client = client_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+            let response = HttpResponse::Forbidden().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                &format!("Access from {} is not allowed", client),
+                None,
+            ));
+            // Logged from the future, which runs with the request id in scope
+            return Box::pin(async move {
+                log::warn!("Refused {} {} from {}: address is not allowed", req.method(), req.path(), client);
+                Ok(req.into_response(response))
+            });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_boxed_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+
+    #[actix_rt::test]
+    async fn test_private_networks_only_with_deny_list() {
+        let config = AccessConfig {
+            allow: vec!["203.0.113.0/24".parse().unwrap()],
+            deny: vec!["192.168.1.13".parse().unwrap()],
+            private_networks_only: true,
+            cors_origins: vec!["https://drop.lan".to_string()],
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&config))
+                .wrap(AccessControl::from_config(&config))
+                .route("/api/ws", web::get().to(HttpResponse::Ok))
+                .route("/healthz", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |peer: &str, path: &str| {
+            let req = test::TestRequest::get().uri(path).peer_addr(peer.parse().unwrap());
+            let app = &app;
+            async move { test::call_service(app, req.to_request()).await.status() }
+        };
+
+        assert_eq!(status("192.168.1.20:5000", "/healthz").await, StatusCode::OK);
+        assert_eq!(status("[fe80::1]:5000", "/api/ws").await, StatusCode::OK);
+        assert_eq!(status("203.0.113.4:5000", "/healthz").await, StatusCode::OK);
+        assert_eq!(status("198.51.100.1:5000", "/api/ws").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("192.168.1.13:5000", "/healthz").await, StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/healthz")
+            .peer_addr("8.8.8.8:5000".parse().unwrap())
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["message"], "Access from 8.8.8.8 is not allowed");
+
+        // Only the configured origin gets CORS headers
+        for (origin, allowed) in [("https://drop.lan", true), ("https://evil.example", false)] {
+            let req = test::TestRequest::get()
+                .uri("/healthz")
+                .peer_addr("127.0.0.1:5000".parse().unwrap())
+                .insert_header(("Origin", origin))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.headers().get("Access-Control-Allow-Origin").is_some(), allowed, "{}", origin);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_no_cross_origin_access_by_default() {
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&AccessConfig::default()))
+                .route("/api/files", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Same-origin pages send an Origin too and are served, just without CORS headers
+        let req = test::TestRequest::post()
+            .uri("/api/files")
+            .insert_header(("Origin", "https://evil.example"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("Access-Control-Allow-Origin").is_none());
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/files")
+            .insert_header(("Origin", "https://evil.example"))
+            .insert_header(("Access-Control-Request-Method", "DELETE"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get("Access-Control-Allow-Origin").is_none());
+    }
+}
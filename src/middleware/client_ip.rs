@@ -0,0 +1,202 @@
+use actix_web::{Error, HttpMessage, dev::{ServiceRequest, ServiceResponse}};
+use actix_web::http::header::{HeaderMap, HeaderName, FORWARDED};
+use actix_service::{Service, Transform};
+use futures::future::{ok, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use crate::net::{canonical, Cidr};
+use crate::proxy_protocol::ProxiedConnections;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Address of the client a request came from, after looking through trusted proxies.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// The client address resolved by [`ClientIpResolver`], or the peer address when it did not run.
+pub fn client_ip(req: &ServiceRequest) -> Option<IpAddr> {
+    req.extensions()
+        .get::<ClientIp>()
+        .map(|client| client.0)
+        .or_else(|| req.peer_addr().map(|addr| canonical(addr.ip())))
+}
+
+/// Works out the real client address of each request and stores it as [`ClientIp`].
+///
+/// Forwarding headers are only believed when the connection comes from a trusted proxy. Hops
+/// are then taken from the right of `Forwarded` (or `X-Forwarded-For` without it) for as long
+/// as each one is a trusted proxy too, so a client cannot pick its address by sending the
+/// header itself.
+#[derive(Clone)]
+pub struct ClientIpResolver {
+    trusted: Arc<Vec<Cidr>>,
+    proxied: Option<ProxiedConnections>,
+}
+
+impl ClientIpResolver {
+    pub fn new(trusted: Vec<Cidr>) -> Self {
+        Self {
+            trusted: Arc::new(trusted),
+            proxied: None,
+        }
+    }
+
+    /// Takes the peer of connections forwarded by the PROXY protocol listener from its header.
+    pub fn with_proxy_protocol(mut self, proxied: ProxiedConnections) -> Self {
+        self.proxied = Some(proxied);
+        self
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn resolve(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = self
+            .proxied
+            .as_ref()
+            .and_then(|proxied| proxied.client_addr(&peer))
+            .unwrap_or(peer);
+        let mut client = canonical(peer.ip());
+
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            // An obfuscated or unknown hop ends the chain at the last address we know
+            match hop {
+                Some(ip) => client = canonical(ip),
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// The `for=` addresses of `Forwarded`, or the entries of `X-Forwarded-For` when there is no
+/// `Forwarded` header, oldest hop first. Hops without a usable address are `None`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+
+    if headers.contains_key(FORWARDED) {
+        values(FORWARDED)
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim().eq_ignore_ascii_case("for").then(|| parse_node(value))
+                })
+                .flatten()
+            })
+            .collect()
+    } else {
+        values(X_FORWARDED_FOR).into_iter().map(parse_node).collect()
+    }
+}
+
+/// Parses `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`, optionally quoted.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ClientIpResolver
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ClientIpMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ClientIpMiddleware {
+            service,
+            resolver: self.clone(),
+        })
+    }
+}
+
+pub struct ClientIpMiddleware<S> {
+    service: S,
+    resolver: ClientIpResolver,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientIpMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(peer) = req.peer_addr() {
+            let client = self.resolver.resolve(peer, req.headers());
+            req.extensions_mut().insert(ClientIp(client));
+        }
+        self.service.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    async fn echo_ip(req: HttpRequest) -> HttpResponse {
+        let ip = req.extensions().get::<ClientIp>().map(|client| client.0.to_string());
+        HttpResponse::Ok().body(ip.unwrap_or_default())
+    }
+
+    #[actix_rt::test]
+    async fn test_forwarding_headers_are_only_believed_from_trusted_proxies() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+        let app = test::init_service(
+            App::new()
+                .wrap(ClientIpResolver::new(trusted))
+                .route("/", web::get().to(echo_ip)),
+        )
+        .await;
+        let resolve = |peer: &str, header: Option<(&str, &str)>| {
+            let mut req = test::TestRequest::get().uri("/").peer_addr(peer.parse().unwrap());
+            if let Some(header) = header {
+                req = req.insert_header(header);
+            }
+            let app = &app;
+            async move { test::call_and_read_body(app, req.to_request()).await }
+        };
+
+        // A client sending the header itself is not believed
+        let spoofed = resolve("198.51.100.1:4000", Some(("X-Forwarded-For", "1.2.3.4"))).await;
+        assert_eq!(spoofed, "198.51.100.1");
+
+        // Through the trusted proxy chain, the first untrusted hop is the client
+        let header = ("X-Forwarded-For", "1.2.3.4, 203.0.113.9, 10.0.0.3");
+        assert_eq!(resolve("10.0.0.2:4000", Some(header)).await, "203.0.113.9");
+
+        let header = ("Forwarded", r#"for=192.0.2.60;proto=https, for="[2001:db8::1]:4711""#);
+        assert_eq!(resolve("[::1]:4000", Some(header)).await, "2001:db8::1");
+        let header = ("Forwarded", "for=unknown");
+        assert_eq!(resolve("10.0.0.2:4000", Some(header)).await, "10.0.0.2");
+
+        assert_eq!(resolve("[::ffff:10.0.0.2]:4000", None).await, "10.0.0.2");
+    }
+}
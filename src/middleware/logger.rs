@@ -1,16 +1,155 @@
-use actix_web::{Error, dev::{ServiceRequest, ServiceResponse}};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, dev::{Payload, ServiceRequest, ServiceResponse}};
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue, USER_AGENT};
 use actix_service::{Service, Transform};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures::future::{ok, Ready, LocalBoxFuture};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
+use std::time::Instant;
+use super::client_ip::client_ip;
 
+/// Log target of the JSON access log lines
+pub const ACCESS_LOG_TARGET: &str = "windrop::access";
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longer or non-printable incoming ids are replaced rather than propagated
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// Query parameters whose values are credentials and never written to the access log
+const REDACTED_QUERY_KEYS: &[&str] = &["token", "resume_token", "access_token", "secret", "password", "key", "pin"];
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: RequestId;
+}
+
+/// Id of a request, taken from its `X-Request-Id` header or generated. Handlers can extract it,
+/// and anything logged while the request is handled is tagged with it.
+#[derive(Debug, Clone)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    fn generate() -> Self {
+        Self(uuid::Uuid::new_v4().to_string().into())
+    }
+
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.into()))
+    }
+
+    /// The id of the request whose handler is running, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT_REQUEST_ID.try_with(Clone::clone).ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for RequestId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        // Only missing when a handler runs without the middleware, e.g. in tests
+        ok(req.extensions().get::<RequestId>().cloned().unwrap_or_else(RequestId::generate))
+    }
+}
+
+/// The device a request was made by, recorded by handlers that identify one so the access log
+/// can name it.
+#[derive(Debug, Clone)]
+pub struct RequestDevice(pub String);
+
+/// Formats log records, appending the id of the request being handled. Access log records are
+/// already JSON and are written as they are.
+pub fn format_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    if record.target() == ACCESS_LOG_TARGET {
+        return writeln!(buf, "{}", record.args());
+    }
+
+    write!(buf, "[{} {} {}] {}", buf.timestamp(), record.level(), record.target(), record.args())?;
+    if let Some(request_id) = RequestId::current() {
+        write!(buf, " request_id={}", request_id)?;
+    }
+    writeln!(buf)
+}
+
+#[derive(Serialize)]
+struct AccessLogEntry {
+    timestamp: DateTime<Utc>,
+    request_id: RequestId,
+    method: String,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+    status: u16,
+    duration_ms: f64,
+    client_ip: Option<String>,
+    user_agent: Option<String>,
+    device_id: Option<String>,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl AccessLogEntry {
+    /// Starts an entry for a request. The status and sizes are filled in once it is answered.
+    fn new(req: &ServiceRequest, request_id: RequestId) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            request_id,
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            query: Some(redact_query(req.query_string())).filter(|query| !query.is_empty()),
+            status: 0,
+            duration_ms: 0.0,
+            client_ip: client_ip(req).map(|ip| ip.to_string()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            device_id: None,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+}
+
+/// Tags every request with an `X-Request-Id` and writes one JSON access log line per request.
+/// The line is written once the response body has been sent, so streamed downloads and
+/// WebSocket sessions are logged with their full duration and size.
 pub struct RequestLogger;
 
 impl<S, B> Transform<S, ServiceRequest> for RequestLogger
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<LoggedBody>;
     type Error = Error;
     type Transform = RequestLoggerMiddleware<S>;
     type InitError = ();
@@ -28,9 +167,9 @@ pub struct RequestLoggerMiddleware<S> {
 impl<S, B> Service<ServiceRequest> for RequestLoggerMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<LoggedBody>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
@@ -38,26 +177,180 @@ where
         self.service.poll_ready(cx)
     }
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let start_time = std::time::Instant::now();
-        let method = req.method().to_string();
-        let path = req.path().to_string();
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let start_time = Instant::now();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        req.extensions_mut().insert(request_id.clone());
+
+        let received = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&received);
+        let payload = req.take_payload().inspect(move |chunk| {
+            if let Ok(data) = chunk {
+                counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        });
+        let payload: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(payload);
+        req.set_payload(Payload::from(payload));
+
+        let mut entry = AccessLogEntry::new(&req, request_id.clone());
 
-        let fut = self.service.call(req);
+        let fut = CURRENT_REQUEST_ID.scope(request_id.clone(), self.service.call(req));
 
         Box::pin(async move {
-            let res = fut.await?;
-            let duration = start_time.elapsed();
-            
-            log::info!(
-                "{} {} - Status: {} - Duration: {:?}",
-                method,
-                path,
-                res.status(),
-                duration
-            );
-            
-            Ok(res)
+            let mut res = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            entry.status = res.status().as_u16();
+            entry.device_id = res
+                .request()
+                .extensions()
+                .get::<RequestDevice>()
+                .map(|device| device.0.clone());
+
+            Ok(res.map_body(|_, body| LoggedBody {
+                body: body.boxed(),
+                entry: Some(entry),
+                started: start_time,
+                received,
+                sent: 0,
+            }))
+        })
+    }
+}
+
+/// Replaces the values of credential parameters in a raw query string with `REDACTED`.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if is_redacted_key(key) => format!("{}=REDACTED", key),
+            _ => pair.to_string(),
         })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Compares the percent-decoded key, so `%74oken` is caught as well as `token`.
+fn is_redacted_key(key: &str) -> bool {
+    let mut decoded = Vec::with_capacity(key.len());
+    let mut bytes = key.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => decoded.push(byte),
+                    None => decoded.extend_from_slice(&hex),
+                }
+            }
+            _ => decoded.push(b),
+        }
+    }
+    let decoded = String::from_utf8_lossy(&decoded).to_lowercase();
+    REDACTED_QUERY_KEYS.contains(&decoded.as_str())
+}
+
+/// Response body that counts what is sent and writes the access log line when dropped.
+pub struct LoggedBody {
+    body: BoxBody,
+    entry: Option<AccessLogEntry>,
+    started: Instant,
+    received: Arc<AtomicU64>,
+    sent: u64,
+}
+
+impl MessageBody for LoggedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.sent += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.duration_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+            entry.bytes_in = self.received.load(Ordering::Relaxed);
+            entry.bytes_out = self.sent;
+            match serde_json::to_string(&entry) {
+                Ok(line) => log::info!(target: ACCESS_LOG_TARGET, "{}", line),
+                Err(e) => log::error!("Failed to serialize access log entry: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn echo_id(request_id: RequestId, body: Bytes) -> HttpResponse {
+        assert_eq!(RequestId::current().unwrap().as_str(), request_id.as_str());
+        HttpResponse::Ok().body(format!("{} {}", request_id, body.len()))
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_is_propagated_or_generated() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestLogger)
+                .route("/echo", web::post().to(echo_id)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("X-Request-Id", "abc-123"))
+            .set_payload("hello")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("X-Request-Id").unwrap(), "abc-123");
+        assert_eq!(test::read_body(resp).await, "abc-123 5");
+
+        // Ids that cannot be echoed safely are replaced
+        let req = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header(("X-Request-Id", "has space"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let generated = resp.headers().get("X-Request-Id").unwrap().to_str().unwrap().to_string();
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+        assert_eq!(test::read_body(resp).await, format!("{} 0", generated));
+    }
+
+    #[actix_rt::test]
+    async fn test_credentials_are_redacted_from_query() {
+        let query = redact_query("name=laptop&token=s3cr3t&%74oken=s3cr3t&PIN=123456&device_id=abc");
+        assert!(!query.contains("s3cr3t"));
+        assert!(!query.contains("123456"));
+        assert_eq!(
+            query,
+            "name=laptop&token=REDACTED&%74oken=REDACTED&PIN=REDACTED&device_id=abc"
+        );
+
+        let req = test::TestRequest::get().uri("/api/ws?name=laptop&token=s3cr3t").to_srv_request();
+        let entry = AccessLogEntry::new(&req, RequestId::generate());
+        assert!(!serde_json::to_string(&entry).unwrap().contains("s3cr3t"));
     }
 }
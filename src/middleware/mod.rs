@@ -1,2 +1,4 @@
+pub mod access_control;
+pub mod client_ip;
 pub mod logger;
 pub mod rate_limit;
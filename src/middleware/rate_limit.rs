@@ -2,44 +2,221 @@ use actix_web::{
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
     Error, HttpResponse,
     body::{BoxBody, MessageBody},
+    http::{header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER}, Method},
 };
 use futures::future::{ok, Ready, LocalBoxFuture};
 use std::{
     task::{Context, Poll},
     sync::{Arc, Mutex},
     collections::HashMap,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
     time::{Instant, Duration},
 };
+use crate::config::RateLimitConfig;
+use crate::models::response::ApiResponse;
+use crate::services::metrics_service::Metrics;
+use super::client_ip::client_ip;
 
+/// Client state is split across this many locks so concurrent requests rarely contend
+const SHARDS: usize = 16;
+/// How often clients whose allowance has fully recovered are forgotten
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+/// `limit` requests per `window`, refilled evenly rather than all at once when the window ends.
+#[derive(Debug, Clone, Copy)]
+struct Policy {
+    limit: u32,
+    window: Duration,
+}
+
+impl Policy {
+    /// Time it takes to earn back one request
+    fn interval(&self) -> Duration {
+        self.window / self.limit
+    }
+}
+
+struct Route {
+    prefix: String,
+    /// Every method matches when empty
+    methods: Vec<Method>,
+    /// `None` exempts the route
+    policy: Option<Policy>,
+}
+
+/// What the limiter decided for one request, and what to tell the client about its allowance.
+#[derive(Debug)]
+struct Decision {
+    policy: Policy,
+    allowed: bool,
+    remaining: u32,
+    /// Until the client's full allowance is available again
+    reset: Duration,
+    /// Until the next request would be allowed; zero when this one was
+    retry_after: Duration,
+}
+
+impl Decision {
+    fn write_headers(&self, headers: &mut HeaderMap) {
+        let values = [
+            (RATELIMIT_LIMIT, self.policy.limit.to_string()),
+            (RATELIMIT_REMAINING, self.remaining.to_string()),
+            (RATELIMIT_RESET, ceil_secs(self.reset).to_string()),
+            (RATELIMIT_POLICY, format!("{};w={}", self.policy.limit, self.policy.window.as_secs())),
+        ];
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(ceil_secs(self.retry_after)));
+        }
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Index of the policy a bucket belongs to (`routes.len()` for the default) and the client IP
+type BucketKey = (usize, String);
+
+struct State {
+    default: Policy,
+    routes: Vec<Route>,
+    /// Per bucket, the time at which the client will have used up its allowance if it keeps
+    /// going (GCRA's "theoretical arrival time"). A client at or past it has a full allowance.
+    shards: Vec<Mutex<HashMap<BucketKey, Instant>>>,
+}
+
+/// Per-IP rate limiting using the generic cell rate algorithm, a token bucket that stores a
+/// single timestamp per client.
 #[derive(Clone)]
 pub struct RateLimiter {
-    requests: Arc<Mutex<HashMap<String, Vec<Instant>>>>,
-    max_requests: usize,
-    window_duration: Duration,
+    state: Arc<State>,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl RateLimiter {
-    pub fn new(max_requests: usize, window_duration: Duration) -> Self {
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        let policy = |max_requests: usize, window: Duration| {
+            (max_requests > 0).then(|| Policy {
+                limit: u32::try_from(max_requests).unwrap_or(u32::MAX),
+                window,
+            })
+        };
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| Route {
+                prefix: route.path.clone(),
+                methods: route
+                    .methods
+                    .iter()
+                    .filter_map(|method| method.parse().ok())
+                    .collect(),
+                policy: policy(route.max_requests, route.window()),
+            })
+            .collect();
+
         RateLimiter {
-            requests: Arc::new(Mutex::new(HashMap::new())),
-            max_requests,
-            window_duration,
+            state: Arc::new(State {
+                default: policy(config.max_requests, config.window())
+                    .expect("rate_limit.max_requests is validated to be positive"),
+                routes,
+                shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            }),
+            metrics: None,
+        }
+    }
+
+    /// Counts rejected requests in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Periodically forgets clients that have their full allowance back, so idle clients do
+    /// not accumulate.
+    pub fn start_eviction(&self) {
+        let limiter = self.clone();
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                let evicted = limiter.evict_idle(Instant::now());
+                if evicted > 0 {
+                    log::debug!("Rate limiter forgot {} idle client(s)", evicted);
+                }
+            }
+        });
+    }
+
+    fn evict_idle(&self, now: Instant) -> usize {
+        self.state
+            .shards
+            .iter()
+            .map(|shard| {
+                let mut buckets = shard.lock().unwrap();
+                let before = buckets.len();
+                buckets.retain(|_, tat| *tat > now);
+                before - buckets.len()
+            })
+            .sum()
+    }
+
+    /// Finds the policy for a request, returning its bucket index, or `None` when it is exempt.
+    fn policy_for(&self, method: &Method, path: &str) -> Option<(usize, Policy)> {
+        let state = &self.state;
+        let matched = state.routes.iter().enumerate().find(|(_, route)| {
+            path.starts_with(&route.prefix)
+                && (route.methods.is_empty() || route.methods.contains(method))
+        });
+        match matched {
+            Some((index, route)) => route.policy.map(|policy| (index, policy)),
+            None => Some((state.routes.len(), state.default)),
         }
     }
 
-    fn is_rate_limited(&self, client_ip: &str) -> bool {
-        let mut requests = self.requests.lock().unwrap();
-        let now = Instant::now();
-        
-        let timestamps = requests.entry(client_ip.to_string()).or_insert_with(Vec::new);
-        timestamps.retain(|&time| now.duration_since(time) < self.window_duration);
-        
-        if timestamps.len() >= self.max_requests {
-            return true;
+    fn check(&self, method: &Method, path: &str, client_ip: &str, now: Instant) -> Option<Decision> {
+        let (index, policy) = self.policy_for(method, path)?;
+        let key = (index, client_ip.to_string());
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut buckets = self.state.shards[hasher.finish() as usize % SHARDS].lock().unwrap();
+
+        let interval = policy.interval();
+        let tat = buckets.get(&key).copied().filter(|tat| *tat > now).unwrap_or(now);
+        let used = tat - now;
+
+        // Allowed as long as taking one more request keeps the debt within one window
+        if used + interval > policy.window {
+            return Some(Decision {
+                policy,
+                allowed: false,
+                remaining: 0,
+                reset: used,
+                retry_after: used + interval - policy.window,
+            });
         }
-        
-        timestamps.push(now);
-        false
+
+        let used = used + interval;
+        buckets.insert(key, now + used);
+        let remaining = (policy.window - used).as_nanos() / interval.as_nanos().max(1);
+        Some(Decision {
+            policy,
+            allowed: true,
+            remaining: u32::try_from(remaining).unwrap_or(u32::MAX),
+            reset: used,
+            retry_after: Duration::ZERO,
+        })
     }
 }
 
@@ -81,15 +258,26 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let client_ip = req
-            .connection_info()
-            .peer_addr()
-            .unwrap_or("unknown")
-            .to_string();
-
-        if self.rate_limiter.is_rate_limited(&client_ip) {
-            let response = HttpResponse::TooManyRequests()
-                .body("Rate limit exceeded. Please try again later.");
+        let client_ip = client_ip(&req).map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+
+        let decision = self
+            .rate_limiter
+            .check(req.method(), req.path(), &client_ip, Instant::now());
+
+        if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+            if let Some(metrics) = &self.rate_limiter.metrics {
+                metrics.rate_limited.inc();
+            }
+            let mut response = HttpResponse::TooManyRequests().json(ApiResponse::<()>::new(
+                1,
+                "error",
+                &format!(
+                    "Rate limit exceeded. Try again in {} second(s).",
+                    ceil_secs(decision.retry_after)
+                ),
+                None,
+            ));
+            decision.write_headers(response.headers_mut());
             return Box::pin(async move {
                 Ok(ServiceResponse::new(
                     req.into_parts().0,
@@ -100,8 +288,110 @@ where
 
         let fut = self.service.call(req);
         Box::pin(async move {
-            let res = fut.await?;
+            let mut res = fut.await?;
+            if let Some(decision) = decision {
+                decision.write_headers(res.headers_mut());
+            }
             Ok(res.map_into_boxed_body())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouteRateLimit;
+
+    fn limiter() -> RateLimiter {
+        let config: RateLimitConfig = toml::from_str(
+            r#"
+            max_requests = 3
+            window_secs = 60
+
+            [[routes]]
+            path = "/api/ws"
+            methods = ["GET"]
+            max_requests = 1
+            window_secs = 10
+
+            [[routes]]
+            path = "/healthz"
+            max_requests = 0
+            window_secs = 0
+            "#,
+        )
+        .unwrap();
+        RateLimiter::from_config(&config)
+    }
+
+    #[test]
+    fn test_gcra_refills_evenly_and_evicts_idle_clients() {
+        let limiter = limiter();
+        let start = Instant::now();
+        let get = Method::GET;
+
+        // A burst of the full allowance, then one request every 20s
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check(&get, "/api/files", "10.0.0.1", start).unwrap();
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let decision = limiter.check(&get, "/api/files", "10.0.0.1", start).unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_secs(20));
+        assert!(limiter.check(&get, "/api/files", "10.0.0.1", start + Duration::from_secs(20)).unwrap().allowed);
+
+        // Routes have their own policy and bucket; exempt routes are not tracked at all
+        assert!(limiter.check(&get, "/api/ws", "10.0.0.1", start).unwrap().allowed);
+        assert!(!limiter.check(&get, "/api/ws", "10.0.0.1", start).unwrap().allowed);
+        // Only GET matches the /api/ws route, so a POST draws on the exhausted default bucket
+        assert!(!limiter.check(&Method::POST, "/api/ws", "10.0.0.1", start).unwrap().allowed);
+        assert!(limiter.check(&get, "/healthz", "10.0.0.1", start).is_none());
+        assert!(limiter.check(&get, "/api/files", "10.0.0.2", start).unwrap().allowed);
+
+        assert_eq!(limiter.evict_idle(start + Duration::from_secs(5)), 0);
+        assert_eq!(limiter.evict_idle(start + Duration::from_secs(120)), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_rejection_has_headers_and_json_body() {
+        use actix_web::{http::StatusCode, test, web, App};
+
+        let mut config = RateLimitConfig {
+            max_requests: 2,
+            window_secs: 60,
+            routes: Vec::new(),
+        };
+        config.routes.push(RouteRateLimit {
+            path: "/exempt".to_string(),
+            methods: Vec::new(),
+            max_requests: 0,
+            window_secs: 0,
+        });
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimiter::from_config(&config))
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/exempt", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.headers().get("RateLimit-Limit").unwrap(), "2");
+        assert_eq!(resp.headers().get("RateLimit-Remaining").unwrap(), "1");
+        assert_eq!(resp.headers().get("RateLimit-Policy").unwrap(), "2;w=60");
+        test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "30");
+        assert_eq!(resp.headers().get("RateLimit-Remaining").unwrap(), "0");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], 1);
+        assert_eq!(body["message"], "Rate limit exceeded. Try again in 30 second(s).");
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/exempt").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("RateLimit-Limit").is_none());
+    }
+}
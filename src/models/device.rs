@@ -1,9 +1,39 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustState {
+    /// Connected but not yet approved by a trusted device
+    Pending,
+    Trusted,
+}
+
+/// Optional parts of the WebSocket protocol, agreed on through `Hello` and `Welcome`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// File chunks sent as binary messages instead of base64 `FileChunk`s
+    BinaryChunks,
+    /// Chunk contents compressed by the sender; relayed as is
+    Compression,
+    /// Chunk contents encrypted between the two devices; relayed as is
+    Encryption,
+    /// A feature from a newer client that this server does not know
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct DeviceInfo {
     pub id: String,
     pub name: String,
     pub last_seen: DateTime<Utc>,
-}
\ No newline at end of file
+    pub trust: TrustState,
+    /// False while a dropped device is within its reconnect grace period
+    pub online: bool,
+    /// Features agreed on with the device's current connection
+    #[serde(default)]
+    pub features: Vec<Feature>,
+}
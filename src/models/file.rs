@@ -1,13 +1,34 @@
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use std::path::PathBuf;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct File {
     pub id: String,
     pub filename: String,
     pub size: u64,
-    #[serde(skip_serializing)]
+    /// Hex-encoded SHA-256 of the contents; absent for files stored before hashing was added
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub uploaded_at: DateTime<Utc>,
+    /// When the file is deleted by the reaper; `None` keeps it until deleted by hand
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Number of downloads after which the file deletes itself
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub download_count: u32,
+    /// Paired device the upload is charged to; `None` for uploads made without a device token
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(skip_serializing, default)]
     pub file_path: PathBuf,
 }
 
@@ -17,7 +38,94 @@ impl File {
             id: Uuid::new_v4().to_string(),
             filename,
             size,
+            sha256: None,
+            uploaded_at: Utc::now(),
+            expires_at: None,
+            max_downloads: None,
+            download_count: 0,
+            owner: None,
             file_path,
         }
     }
+
+    pub fn with_sha256(mut self, sha256: String) -> Self {
+        self.sha256 = Some(sha256);
+        self
+    }
+
+    pub fn with_owner(mut self, owner: Option<String>) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+            || self.max_downloads.is_some_and(|max| self.download_count >= max)
+    }
+}
+
+/// Retention settings a client may choose at upload time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RetentionOptions {
+    /// Lifetime in seconds; the server default applies when omitted
+    pub ttl_secs: Option<u64>,
+    pub max_downloads: Option<u32>,
+}
+
+/// Links an upload to a WebSocket transfer, so both devices get `TransferProgress` events.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadTransfer {
+    /// An accepted transfer the uploading device is the sender of
+    pub transfer_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileSort {
+    #[default]
+    UploadedAt,
+    Filename,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query parameters for `GET /api/files`.
+#[derive(Debug, Default, Deserialize)]
+pub struct FileListQuery {
+    /// 1-based page number
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    #[serde(default)]
+    pub sort: FileSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Case-insensitive substring the filename must contain
+    pub filename: Option<String>,
+}
+
+impl FileListQuery {
+    pub fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> usize {
+        self.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileList {
+    pub files: Vec<File>,
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
 }
@@ -1,3 +1,6 @@
 pub mod file;
 pub mod response;
 pub mod device;
+pub mod upload;
+pub mod usage;
+pub mod tls;
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Envelope of every JSON response. `code` is `0` on success and `1` on error; `data` is only
+/// set on success.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ApiResponse<T> {
     pub code: i32,
     pub status: String,
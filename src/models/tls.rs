@@ -0,0 +1,10 @@
+use serde::Serialize;
+
+/// What clients need to know to pin the server's certificate.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TlsInfo {
+    pub enabled: bool,
+    pub self_signed: bool,
+    /// SHA-256 of the served certificate, as colon-separated hex
+    pub fingerprint_sha256: Option<String>,
+}
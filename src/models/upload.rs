@@ -0,0 +1,125 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use uuid::Uuid;
+use super::file::RetentionOptions;
+
+/// Half-open byte range `[start, end)` of an upload that has been written to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub received: Vec<ByteRange>,
+    /// Digest the assembled file must match on finalize, if the client supplied one
+    pub sha256: Option<String>,
+    pub retention: RetentionOptions,
+    /// Device whose quota the upload counts against
+    pub owner: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// When the session last received data; idle sessions are discarded
+    pub updated_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub part_path: PathBuf,
+    /// Chunk requests currently writing to the part file
+    #[serde(skip)]
+    pub writers: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadRequest {
+    pub filename: String,
+    pub size: u64,
+    /// Optional hex SHA-256 the finished upload is verified against
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(flatten)]
+    pub retention: RetentionOptions,
+}
+
+impl UploadSession {
+    pub fn new(filename: String, size: u64, part_dir: &std::path::Path) -> Self {
+        let id = Uuid::new_v4().to_string();
+        let part_path = part_dir.join(format!("{}.part", id));
+        let now = Utc::now();
+
+        Self {
+            id,
+            filename,
+            size,
+            received: Vec::new(),
+            sha256: None,
+            retention: RetentionOptions::default(),
+            owner: None,
+            created_at: now,
+            updated_at: now,
+            part_path,
+            writers: 0,
+        }
+    }
+
+    /// Records `[start, end)` as received, merging it with any overlapping or adjacent ranges.
+    pub fn mark_received(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+
+        let mut merged = ByteRange { start, end };
+        let mut ranges = Vec::with_capacity(self.received.len() + 1);
+        for range in self.received.drain(..) {
+            if range.end < merged.start || range.start > merged.end {
+                ranges.push(range);
+            } else {
+                merged.start = merged.start.min(range.start);
+                merged.end = merged.end.max(range.end);
+            }
+        }
+        ranges.push(merged);
+        ranges.sort_by_key(|range| range.start);
+        self.received = ranges;
+    }
+
+    /// Length of the contiguous prefix received so far, i.e. where a sequential client resumes.
+    pub fn offset(&self) -> u64 {
+        match self.received.first() {
+            Some(range) if range.start == 0 => range.end,
+            _ => 0,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset() == self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mark_received_merges_ranges() {
+        let mut session = UploadSession::new("a.bin".to_string(), 30, std::path::Path::new("/tmp"));
+
+        session.mark_received(10, 20);
+        assert_eq!(session.offset(), 0);
+
+        session.mark_received(25, 30);
+        session.mark_received(0, 10);
+        assert_eq!(
+            session.received,
+            vec![ByteRange { start: 0, end: 20 }, ByteRange { start: 25, end: 30 }]
+        );
+        assert_eq!(session.offset(), 20);
+        assert!(!session.is_complete());
+
+        session.mark_received(15, 27);
+        assert_eq!(session.received, vec![ByteRange { start: 0, end: 30 }]);
+        assert!(session.is_complete());
+    }
+}
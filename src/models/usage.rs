@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// Storage limits in bytes; `None` leaves a dimension unlimited.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct QuotaLimits {
+    pub max_file_size: Option<u64>,
+    /// Total across every stored file and upload in progress
+    pub global_quota: Option<u64>,
+    /// Per paired device; uploads without a device token share one anonymous allowance
+    pub device_quota: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceUsage {
+    /// `None` for uploads made without a device token
+    pub device_id: Option<String>,
+    pub used_bytes: u64,
+    pub file_count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageUsage {
+    pub used_bytes: u64,
+    /// Bytes held by uploads in progress
+    pub reserved_bytes: u64,
+    pub file_count: usize,
+    pub limits: QuotaLimits,
+    pub devices: Vec<DeviceUsage>,
+}
+
+/// Usage as shown to a device: the server totals and its own share, without other devices.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceUsageReport {
+    pub used_bytes: u64,
+    pub reserved_bytes: u64,
+    pub file_count: usize,
+    pub limits: QuotaLimits,
+    pub device: DeviceUsage,
+}
+
+impl StorageUsage {
+    pub fn report_for(self, device_id: &str) -> DeviceUsageReport {
+        let device = self
+            .devices
+            .into_iter()
+            .find(|device| device.device_id.as_deref() == Some(device_id))
+            .unwrap_or_else(|| DeviceUsage {
+                device_id: Some(device_id.to_string()),
+                used_bytes: 0,
+                file_count: 0,
+            });
+
+        DeviceUsageReport {
+            used_bytes: self.used_bytes,
+            reserved_bytes: self.reserved_bytes,
+            file_count: self.file_count,
+            limits: self.limits,
+            device,
+        }
+    }
+}
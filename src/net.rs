@@ -0,0 +1,143 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde::Deserialize;
+
+/// An IP network such as `10.0.0.0/8`. A bare address stands for just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix_len) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.prefix_len) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Private (RFC 1918 and unique local), link-local and loopback ranges.
+pub const PRIVATE_NETWORKS: [&str; 8] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "127.0.0.0/8",
+    "fc00::/7",
+    "fe80::/10",
+    "::1/128",
+];
+
+pub fn private_networks() -> Vec<Cidr> {
+    PRIVATE_NETWORKS
+        .iter()
+        .map(|range| range.parse().expect("private network ranges are valid"))
+        .collect()
+}
+
+/// Treats IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), as seen on dual-stack sockets, as the
+/// IPv4 addresses they are.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+fn v4_mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0)
+}
+
+fn v6_mask(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map(canonical)
+            .map_err(|_| format!("{:?} is not an IP address or CIDR range", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("{:?} has an invalid prefix length", s))?,
+            None => max_len,
+        };
+
+        // Host bits are cleared, so 10.1.2.3/8 means 10.0.0.0/8
+        let network = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & v4_mask(prefix_len)).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & v6_mask(prefix_len)).into()),
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_parsing_and_matching() {
+        let lan: Cidr = "192.168.1.7/24".parse().unwrap();
+        assert_eq!(lan.to_string(), "192.168.1.0/24");
+        assert!(lan.contains("192.168.1.200".parse().unwrap()));
+        assert!(lan.contains("::ffff:192.168.1.9".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains("fe80::1".parse().unwrap()));
+
+        let host: Cidr = "10.0.0.1".parse().unwrap();
+        assert!(host.contains("10.0.0.1".parse().unwrap()));
+        assert!(!host.contains("10.0.0.2".parse().unwrap()));
+
+        let everything: Cidr = "::/0".parse().unwrap();
+        assert!(everything.contains("2001:db8::1".parse().unwrap()));
+        let ula: Cidr = "fc00::/7".parse().unwrap();
+        assert!(ula.contains("fd12:3456::1".parse().unwrap()));
+        assert!(!ula.contains("fe80::1".parse().unwrap()));
+
+        let private = private_networks();
+        for ip in ["172.31.0.1", "169.254.3.4", "127.0.0.1", "fe80::2", "::ffff:192.168.0.1"] {
+            assert!(private.iter().any(|cidr| cidr.contains(ip.parse().unwrap())), "{}", ip);
+        }
+        assert!(!private.iter().any(|cidr| cidr.contains("172.32.0.1".parse().unwrap())));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+}
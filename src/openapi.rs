@@ -0,0 +1,93 @@
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::controllers::{file_controller, websocket_controller};
+use crate::models::device::{DeviceInfo, Feature, TrustState};
+use crate::models::file::File;
+use crate::websocket::message::FileTransferMessage;
+
+/// Where the spec is served; Swagger UI loads it from here.
+pub const SPEC_PATH: &str = "/api-docs/openapi.json";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Windrop",
+        description = "File drop server for devices on the same network"
+    ),
+    paths(
+        file_controller::upload_file,
+        file_controller::get_file,
+        websocket_controller::websocket_route,
+    ),
+    components(schemas(File, DeviceInfo, TrustState, Feature, FileTransferMessage)),
+    modifiers(&DeviceToken),
+    tags(
+        (name = "files", description = "Uploading and downloading files"),
+        (name = "websocket", description = "Device presence, pairing and transfer messages"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Multipart form taken by `POST /api/upload`. Only used to describe the request body.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+/// Raw file contents in a download response.
+#[derive(ToSchema)]
+#[schema(value_type = String, format = Binary)]
+#[allow(dead_code)]
+pub struct FileContents(Vec<u8>);
+
+/// Token a paired device receives in `PairingApproved`, sent as `Authorization: Bearer <token>`.
+struct DeviceToken;
+
+impl Modify for DeviceToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "device_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use utoipa_swagger_ui::SwaggerUi;
+
+    #[actix_rt::test]
+    async fn test_spec_and_swagger_ui_are_served() {
+        let app = test::init_service(
+            App::new().service(SwaggerUi::new("/swagger-ui/{_:.*}").url(SPEC_PATH, ApiDoc::openapi())),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri(SPEC_PATH).to_request();
+        let spec: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        for path in ["/api/upload", "/api/files/{id}", "/api/ws"] {
+            assert!(spec["paths"][path].is_object(), "{} is not documented", path);
+        }
+        let schemas = &spec["components"]["schemas"];
+        for schema in ["File", "DeviceInfo", "FileTransferMessage"] {
+            assert!(schemas[schema].is_object(), "{} schema is missing", schema);
+        }
+        assert!(schemas["File"]["properties"].get("file_path").is_none());
+        assert!(schemas["ApiResponse_File"]["properties"]["data"]["properties"]["filename"].is_object());
+        assert_eq!(
+            spec["paths"]["/api/files/{id}"]["get"]["responses"]["200"]["content"]["application/octet-stream"]
+                ["schema"]["$ref"],
+            "#/components/schemas/FileContents"
+        );
+        assert_eq!(schemas["FileContents"]["format"], "binary");
+
+        let req = test::TestRequest::get().uri("/swagger-ui/").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
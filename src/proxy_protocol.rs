@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::net::Cidr;
+
+/// A proxy that connects but never sends its header is dropped after this long
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest v1 header the specification allows, including the CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Client addresses announced in PROXY headers, keyed by the address each forwarded connection
+/// reaches the HTTP server from.
+#[derive(Clone, Default)]
+pub struct ProxiedConnections {
+    clients: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+}
+
+impl ProxiedConnections {
+    fn insert(&self, local: SocketAddr, client: SocketAddr) {
+        self.clients.lock().unwrap().insert(local, client);
+    }
+
+    fn remove(&self, local: &SocketAddr) {
+        self.clients.lock().unwrap().remove(local);
+    }
+
+    /// The client behind the forwarded connection the server sees coming from `peer`.
+    pub fn client_addr(&self, peer: &SocketAddr) -> Option<SocketAddr> {
+        self.clients.lock().unwrap().get(peer).copied()
+    }
+}
+
+/// Accepts connections from trusted proxies on `listener`, strips their PROXY header and
+/// forwards them to the HTTP server at `upstream`, recording who the client was.
+pub async fn serve(listener: TcpListener, upstream: SocketAddr, trusted: Vec<Cidr>, connections: ProxiedConnections) {
+    let trusted = Arc::new(trusted);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        if !trusted.iter().any(|cidr| cidr.contains(peer.ip())) {
+            log::warn!("Refusing connection from {}, which is not a trusted proxy", peer);
+            continue;
+        }
+
+        let connections = connections.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = forward(stream, peer, upstream, &connections).await {
+                log::warn!("Proxied connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn forward(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    upstream: SocketAddr,
+    connections: &ProxiedConnections,
+) -> io::Result<()> {
+    let client = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY header received"))??
+        .unwrap_or(peer);
+
+    let mut server = TcpStream::connect(upstream).await?;
+    let local = server.local_addr()?;
+    connections.insert(local, client);
+    let result = tokio::io::copy_bidirectional(&mut stream, &mut server).await;
+    connections.remove(&local);
+    result.map(|_| ())
+}
+
+/// Reads a PROXY protocol v1 or v2 header and nothing after it. Returns the client address, or
+/// `None` when the proxy does not know it (v1 `UNKNOWN`, v2 `LOCAL` or non-IP families).
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions' headers are longer than the v2 signature, so this never reads too far
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(malformed("connection does not start with a PROXY header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(malformed("PROXY v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| malformed("PROXY v1 header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| malformed("invalid PROXY v1 source address"))?;
+            let port = source_port.parse::<u16>().map_err(|_| malformed("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(malformed("invalid PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(malformed("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, e.g. a health check
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(malformed("unsupported PROXY v2 command")),
+    }
+
+    // Anything past the addresses is TLVs, which are not needed
+    let client = match family >> 4 {
+        0x1 if len >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))
+        }
+        0x2 if len >= 36 => {
+            let octets: [u8; 16] = addresses[..16].try_into().unwrap();
+            SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([addresses[32], addresses[33]]))
+        }
+        0x1 | 0x2 => return Err(malformed("PROXY v2 address block is too short")),
+        _ => return Ok(None),
+    };
+    Ok(Some(client))
+}
+
+fn malformed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_reads_v1_and_v2_headers_only() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\nGET / HTTP/1.1\r\n";
+        let client = read_header(&mut stream).await.unwrap();
+        assert_eq!(client, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\nGET";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+        assert_eq!(stream, b"GET");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 39]);
+        header.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        header.extend_from_slice(&[0xc7, 0x38, 0x01, 0xbb]);
+        // A TLV the parser skips
+        header.extend_from_slice(&[0x04, 0x00, 0x00]);
+        header.extend_from_slice(b"GET");
+        let mut stream = header.as_slice();
+        let client = read_header(&mut stream).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:51000".parse().unwrap()));
+        assert_eq!(stream, b"GET");
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -22,4 +22,9 @@ impl FileRepository {
         let storage = self.storage.lock().unwrap();
         storage.get(file_id).cloned()
     }
+
+    pub fn remove(&self, file_id: &str) -> Option<File> {
+        let mut storage = self.storage.lock().unwrap();
+        storage.remove(file_id)
+    }
 }
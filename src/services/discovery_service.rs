@@ -1,30 +1,145 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
-use chrono::Utc;
-use crate::models::device::DeviceInfo;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use chrono::{DateTime, Duration, Utc};
+use crate::models::device::{DeviceInfo, Feature, TrustState};
+use crate::storage::trust_store::{generate_token, hash_token, TrustStore};
+use crate::websocket::connection::Deliver;
+use crate::websocket::handshake::LEGACY_FEATURES;
+use crate::websocket::message::FileTransferMessage;
+use crate::websocket::registry::SessionRegistry;
+
+const PAIRING_TIMEOUT_SECS: i64 = 120;
+const MAX_PIN_ATTEMPTS: u32 = 3;
+const RECONNECT_GRACE_SECS: i64 = 60;
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+/// Setup PIN that lets the first device pair while no device is trusted. It is shown only in the
+/// server log, so whoever reads the log decides which device becomes the first trusted one.
+struct BootstrapPin {
+    pin: String,
+    attempts: u32,
+}
+
+struct PendingPairing {
+    device_id: String,
+    pin: String,
+    expires_at: DateTime<Utc>,
+    attempts: u32,
+}
+
+/// How a newly connected, untrusted device gets paired.
+pub enum PairingStart {
+    /// No device is trusted yet; this one must send the setup PIN from the server log
+    Bootstrap { resume_token: String },
+    /// A trusted device must approve the pairing by entering `pin`
+    Pending {
+        resume_token: String,
+        pairing_id: String,
+        pin: String,
+        expires_at: DateTime<Utc>,
+    },
+}
 
 pub struct DiscoveryService {
     devices: RwLock<HashMap<String, DeviceInfo>>,
+    trust_store: Mutex<TrustStore>,
+    pairings: Mutex<HashMap<String, PendingPairing>>,
+    /// `None` once a device is trusted
+    bootstrap: Mutex<Option<BootstrapPin>>,
+    /// Hashes of the resume tokens that let an unpaired device reconnect under its id
+    claims: Mutex<HashMap<String, String>>,
+    queues: Mutex<HashMap<String, VecDeque<FileTransferMessage>>>,
+    /// Sessions that presence changes are pushed to
+    sessions: Option<Arc<SessionRegistry>>,
+    /// How long a dropped device keeps its identity and queue while waiting to reconnect
+    reconnect_grace: Duration,
 }
 
 impl DiscoveryService {
-    pub fn new() -> Self {
+    pub fn new(trust_store: TrustStore) -> Self {
+        let bootstrap = trust_store.is_empty().then(|| BootstrapPin {
+            pin: generate_pin(),
+            attempts: 0,
+        });
+
         Self {
             devices: RwLock::new(HashMap::new()),
+            trust_store: Mutex::new(trust_store),
+            pairings: Mutex::new(HashMap::new()),
+            bootstrap: Mutex::new(bootstrap),
+            claims: Mutex::new(HashMap::new()),
+            queues: Mutex::new(HashMap::new()),
+            sessions: None,
+            reconnect_grace: Duration::seconds(RECONNECT_GRACE_SECS),
         }
     }
 
+    /// Pushes `DeviceJoined`, `DeviceUpdated` and `DeviceLeft` to the trusted devices connected
+    /// to `sessions` whenever a device comes, changes or goes.
+    pub fn with_sessions(mut self, sessions: Arc<SessionRegistry>) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_reconnect_grace(mut self, grace: Duration) -> Self {
+        self.reconnect_grace = grace;
+        self
+    }
+
+    /// Registers a connecting device, or brings a known device back online under the same id.
     pub fn register_device(&self, id: String, name: String) {
+        let trusted = self.trust_store.lock().unwrap().contains(&id);
+        let mut devices = self.devices.write().unwrap();
+
+        if let Some(device) = devices.get_mut(&id) {
+            device.name = name;
+            device.last_seen = Utc::now();
+            device.online = true;
+            // The new connection has not said what it supports yet
+            device.features = LEGACY_FEATURES.to_vec();
+            if trusted {
+                device.trust = TrustState::Trusted;
+            }
+            drop(devices);
+            self.announce_update(&id);
+            return;
+        }
+
         let device = DeviceInfo {
             id: id.clone(),
             name,
             last_seen: Utc::now(),
+            trust: if trusted { TrustState::Trusted } else { TrustState::Pending },
+            online: true,
+            features: LEGACY_FEATURES.to_vec(),
         };
+        devices.insert(id.clone(), device.clone());
+        drop(devices);
+        self.announce(
+            &id,
+            FileTransferMessage::DeviceJoined {
+                device,
+                timestamp: Utc::now(),
+            },
+        );
+    }
+
+    /// Records the features agreed on with a device's connection.
+    pub fn set_features(&self, id: &str, features: Vec<Feature>) {
+        if let Some(device) = self.devices.write().unwrap().get_mut(id) {
+            device.features = features;
+        }
+        self.announce_update(id);
+    }
 
+    pub fn supports(&self, id: &str, feature: Feature) -> bool {
         self.devices
-            .write()
+            .read()
             .unwrap()
-            .insert(id, device);
+            .get(id)
+            .is_some_and(|device| device.features.contains(&feature))
     }
 
     pub fn update_device_timestamp(&self, id: &str) {
@@ -34,19 +149,459 @@ impl DiscoveryService {
     }
 
     pub fn remove_device(&self, id: &str) {
-        self.devices.write().unwrap().remove(id);
+        let removed = self.devices.write().unwrap().remove(id).is_some();
+        self.queues.lock().unwrap().remove(id);
+        self.claims.lock().unwrap().remove(id);
+        self.pairings
+            .lock()
+            .unwrap()
+            .retain(|_, pairing| pairing.device_id != id);
+        if removed {
+            self.announce_left(id);
+        }
     }
 
-    pub fn get_nearby_devices(&self) -> Vec<DeviceInfo> {
+    /// Keeps a device that dropped its connection, so messages for it can queue until it
+    /// reconnects within the grace period.
+    pub fn mark_offline(&self, id: &str) {
+        if let Some(device) = self.devices.write().unwrap().get_mut(id) {
+            device.online = false;
+            device.last_seen = Utc::now();
+        }
+        self.pairings
+            .lock()
+            .unwrap()
+            .retain(|_, pairing| pairing.device_id != id);
+        self.announce_update(id);
+    }
+
+    /// Forgets devices that stayed offline past the grace period, along with their queues, and
+    /// returns their ids.
+    fn prune_offline(&self) -> Vec<String> {
         let now = Utc::now();
+        let mut expired = Vec::new();
+        self.devices.write().unwrap().retain(|id, device| {
+            let keep = device.online || now - device.last_seen < self.reconnect_grace;
+            if !keep {
+                expired.push(id.clone());
+            }
+            keep
+        });
+
+        if !expired.is_empty() {
+            let mut queues = self.queues.lock().unwrap();
+            let mut claims = self.claims.lock().unwrap();
+            for id in &expired {
+                log::info!("Device {} did not reconnect, forgetting it", id);
+                queues.remove(id);
+                claims.remove(id);
+            }
+        }
+        for id in &expired {
+            self.announce_left(id);
+        }
+        expired
+    }
+
+    /// Periodically forgets devices that did not reconnect in time, so they are announced as
+    /// gone even when nothing else asks about devices.
+    pub fn start_pruning(self: &Arc<Self>, interval: std::time::Duration) {
+        let service = Arc::clone(self);
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                service.prune_offline();
+            }
+        });
+    }
+
+    /// Tells the connected trusted devices other than `subject` about a change to it.
+    fn announce(&self, subject: &str, event: FileTransferMessage) {
+        let Some(sessions) = &self.sessions else {
+            return;
+        };
+        for device_id in self.trusted_device_ids() {
+            if device_id == subject {
+                continue;
+            }
+            if let Some(addr) = sessions.get(&device_id) {
+                addr.do_send(Deliver(event.clone()));
+            }
+        }
+    }
+
+    fn announce_update(&self, id: &str) {
+        let device = self.devices.read().unwrap().get(id).cloned();
+        if let Some(device) = device {
+            self.announce(
+                id,
+                FileTransferMessage::DeviceUpdated {
+                    device,
+                    timestamp: Utc::now(),
+                },
+            );
+        }
+    }
+
+    fn announce_left(&self, id: &str) {
+        self.announce(
+            id,
+            FileTransferMessage::DeviceLeft {
+                device_id: id.to_string(),
+                timestamp: Utc::now(),
+            },
+        );
+    }
+
+    /// Holds a message for an offline device until it reconnects.
+    pub fn queue_message(&self, id: &str, message: FileTransferMessage) -> Result<(), String> {
+        self.prune_offline();
+        if !self.is_trusted(id) {
+            return Err(format!("Receiver {} is unknown", id));
+        }
+
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(id.to_string()).or_default();
+        if queue.len() >= MAX_QUEUED_MESSAGES {
+            return Err(format!("Receiver {} is offline and its queue is full", id));
+        }
+        queue.push_back(message);
+        Ok(())
+    }
+
+    /// Drains the messages that queued up while a device was offline, oldest first.
+    pub fn take_queued(&self, id: &str) -> Vec<FileTransferMessage> {
+        self.queues
+            .lock()
+            .unwrap()
+            .remove(id)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+
+    /// Every known device: the connected ones and those waiting to reconnect.
+    pub fn get_nearby_devices(&self) -> Vec<DeviceInfo> {
+        self.prune_offline();
+        self.devices.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn is_trusted(&self, id: &str) -> bool {
+        self.devices
+            .read()
+            .unwrap()
+            .get(id)
+            .is_some_and(|device| device.trust == TrustState::Trusted)
+    }
+
+    /// Ids of the trusted devices currently known, including those offline but still within
+    /// the reconnect grace period. Callers reach the connected ones through `SessionRegistry`.
+    pub fn trusted_device_ids(&self) -> Vec<String> {
         self.devices
             .read()
             .unwrap()
             .values()
-            .filter(|device| {
-                (now - device.last_seen).num_seconds() < 30
-            })
-            .cloned()
+            .filter(|device| device.trust == TrustState::Trusted)
+            .map(|device| device.id.clone())
             .collect()
     }
-}
\ No newline at end of file
+
+    /// Number of devices currently known, online or waiting to reconnect, and how many of them
+    /// are trusted.
+    pub fn device_counts(&self) -> (usize, usize) {
+        self.prune_offline();
+        let devices = self.devices.read().unwrap();
+        let trusted = devices
+            .values()
+            .filter(|device| device.trust == TrustState::Trusted)
+            .count();
+        (devices.len(), trusted)
+    }
+
+    /// Resolves a device token presented on reconnect to the device id it was issued for.
+    pub fn authenticate(&self, token: &str) -> Option<String> {
+        self.trust_store.lock().unwrap().authenticate(token)
+    }
+
+    /// Resolves a resume token presented by an unpaired device to the id it was issued for.
+    pub fn resume(&self, token: &str) -> Option<String> {
+        let token_sha256 = hash_token(token);
+        self.claims
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, claim)| **claim == token_sha256)
+            .map(|(device_id, _)| device_id.clone())
+    }
+
+    /// Issues a fresh resume token for `device_id`, replacing the one it had.
+    fn issue_resume_token(&self, device_id: &str) -> String {
+        let token = generate_token();
+        self.claims
+            .lock()
+            .unwrap()
+            .insert(device_id.to_string(), hash_token(&token));
+        token
+    }
+
+    /// The setup PIN the first device must send, while no device is trusted. The server logs
+    /// it at startup.
+    pub fn bootstrap_pin(&self) -> Option<String> {
+        self.bootstrap.lock().unwrap().as_ref().map(|bootstrap| bootstrap.pin.clone())
+    }
+
+    /// Trusts `device_id` as the first device if `pin` is the setup PIN, returning its token.
+    ///
+    /// After too many wrong PINs the setup PIN is locked until the server restarts.
+    pub fn bootstrap(&self, device_id: &str, pin: &str) -> Result<String, String> {
+        let mut bootstrap = self.bootstrap.lock().unwrap();
+        let Some(setup) = bootstrap.as_mut() else {
+            return Err("A device is already paired; ask it to approve this one".to_string());
+        };
+        if setup.attempts >= MAX_PIN_ATTEMPTS {
+            return Err("Too many wrong setup PINs; restart the server for a new one".to_string());
+        }
+        if setup.pin != pin {
+            setup.attempts += 1;
+            log::warn!("Wrong setup PIN from device {} ({} of {})", device_id, setup.attempts, MAX_PIN_ATTEMPTS);
+            return Err("Incorrect setup PIN".to_string());
+        }
+
+        let token = self
+            .trust_store
+            .lock()
+            .unwrap()
+            .trust(device_id, &self.device_name(device_id))
+            .map_err(|e| format!("Failed to record trusted device: {}", e))?;
+        *bootstrap = None;
+        drop(bootstrap);
+        self.set_trust(device_id, TrustState::Trusted);
+        log::info!("Device {} paired with the setup PIN", device_id);
+        Ok(token)
+    }
+
+    /// Starts pairing for an untrusted device, replacing any pairing it already had open and
+    /// the resume token it reconnected with.
+    pub fn begin_pairing(&self, device_id: &str) -> io::Result<PairingStart> {
+        let resume_token = self.issue_resume_token(device_id);
+        if self.bootstrap.lock().unwrap().is_some() {
+            return Ok(PairingStart::Bootstrap { resume_token });
+        }
+
+        let now = Utc::now();
+        let mut pairings = self.pairings.lock().unwrap();
+        pairings.retain(|_, pairing| pairing.expires_at > now && pairing.device_id != device_id);
+
+        let pairing_id = uuid::Uuid::new_v4().to_string();
+        let pin = generate_pin();
+        let expires_at = now + Duration::seconds(PAIRING_TIMEOUT_SECS);
+
+        pairings.insert(
+            pairing_id.clone(),
+            PendingPairing {
+                device_id: device_id.to_string(),
+                pin: pin.clone(),
+                expires_at,
+                attempts: 0,
+            },
+        );
+
+        Ok(PairingStart::Pending {
+            resume_token,
+            pairing_id,
+            pin,
+            expires_at,
+        })
+    }
+
+    /// Completes a pairing when `pin` matches, returning the paired device id and its new token.
+    ///
+    /// The pairing is abandoned after too many wrong PINs.
+    pub fn approve_pairing(&self, pairing_id: &str, pin: &str) -> Result<(String, String), String> {
+        let device_id = {
+            let mut pairings = self.pairings.lock().unwrap();
+            let pairing = pairings
+                .get_mut(pairing_id)
+                .filter(|pairing| pairing.expires_at > Utc::now())
+                .ok_or_else(|| "Pairing request not found or expired".to_string())?;
+
+            if pairing.pin != pin {
+                pairing.attempts += 1;
+                if pairing.attempts >= MAX_PIN_ATTEMPTS {
+                    pairings.remove(pairing_id);
+                    return Err("Incorrect PIN; pairing cancelled".to_string());
+                }
+                return Err("Incorrect PIN".to_string());
+            }
+
+            pairings.remove(pairing_id).unwrap().device_id
+        };
+
+        let token = self
+            .trust_store
+            .lock()
+            .unwrap()
+            .trust(&device_id, &self.device_name(&device_id))
+            .map_err(|e| format!("Failed to record trusted device: {}", e))?;
+        self.set_trust(&device_id, TrustState::Trusted);
+        log::info!("Device {} paired", device_id);
+
+        Ok((device_id, token))
+    }
+
+    /// Drops an open pairing, returning the device that was waiting on it.
+    pub fn reject_pairing(&self, pairing_id: &str) -> Option<String> {
+        self.pairings
+            .lock()
+            .unwrap()
+            .remove(pairing_id)
+            .map(|pairing| pairing.device_id)
+    }
+
+    fn device_name(&self, id: &str) -> String {
+        self.devices
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|device| device.name.clone())
+            .unwrap_or_default()
+    }
+
+    fn set_trust(&self, id: &str, trust: TrustState) {
+        if trust == TrustState::Trusted {
+            // A paired device reconnects with its token instead
+            self.claims.lock().unwrap().remove(id);
+        }
+        if let Some(device) = self.devices.write().unwrap().get_mut(id) {
+            device.trust = trust;
+        }
+        self.announce_update(id);
+    }
+}
+
+/// Six random digits.
+fn generate_pin() -> String {
+    let random = u128::from_be_bytes(*uuid::Uuid::new_v4().as_bytes());
+    format!("{:06}", random % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pairing_issues_token_after_pin_approval() {
+        let service = DiscoveryService::new(TrustStore::in_memory());
+        service.register_device("first".to_string(), "laptop".to_string());
+        service.register_device("second".to_string(), "phone".to_string());
+
+        // The first device needs the setup PIN from the server log
+        assert!(matches!(service.begin_pairing("first"), Ok(PairingStart::Bootstrap { .. })));
+        let setup_pin = service.bootstrap_pin().unwrap();
+        let wrong_pin = if setup_pin == "000000" { "111111" } else { "000000" };
+        assert!(service.bootstrap("first", wrong_pin).is_err());
+        assert!(!service.is_trusted("first"));
+        let token = service.bootstrap("first", &setup_pin).unwrap();
+        assert!(service.is_trusted("first"));
+        assert_eq!(service.authenticate(&token).as_deref(), Some("first"));
+        assert!(service.bootstrap_pin().is_none());
+        assert!(service.bootstrap("second", &setup_pin).is_err());
+
+        let Ok(PairingStart::Pending { pairing_id, pin, .. }) = service.begin_pairing("second") else {
+            panic!("second device should need approval");
+        };
+        assert!(!service.is_trusted("second"));
+
+        let wrong_pin = if pin == "000000" { "111111" } else { "000000" };
+        assert!(service.approve_pairing(&pairing_id, wrong_pin).is_err());
+
+        let (device_id, token) = service.approve_pairing(&pairing_id, &pin).unwrap();
+        assert_eq!(device_id, "second");
+        assert!(service.is_trusted("second"));
+        assert_eq!(service.authenticate(&token).as_deref(), Some("second"));
+        assert!(service.authenticate("forged").is_none());
+    }
+
+    #[test]
+    fn test_reconnect_keeps_identity_and_queue() {
+        let service = DiscoveryService::new(TrustStore::in_memory());
+        service.register_device("phone".to_string(), "phone".to_string());
+        let pin = service.bootstrap_pin().unwrap();
+        service.bootstrap("phone", &pin).unwrap();
+
+        service.mark_offline("phone");
+        service
+            .queue_message("phone", FileTransferMessage::error("while you were away"))
+            .unwrap();
+        assert!(service.queue_message("stranger", FileTransferMessage::error("hi")).is_err());
+
+        service.register_device("phone".to_string(), "phone (renamed)".to_string());
+        let devices = service.get_nearby_devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "phone (renamed)");
+        assert!(devices[0].online);
+        assert!(service.is_trusted("phone"));
+
+        let queued = service.take_queued("phone");
+        assert!(matches!(
+            queued.as_slice(),
+            [FileTransferMessage::Error { message, .. }] if message == "while you were away"
+        ));
+        assert!(service.take_queued("phone").is_empty());
+    }
+
+    #[test]
+    fn test_setup_pin_locks_after_too_many_attempts() {
+        let service = DiscoveryService::new(TrustStore::in_memory());
+        service.register_device("intruder".to_string(), "intruder".to_string());
+        let pin = service.bootstrap_pin().unwrap();
+        let wrong_pin = if pin == "000000" { "111111" } else { "000000" };
+        for _ in 0..MAX_PIN_ATTEMPTS {
+            assert_eq!(service.bootstrap("intruder", wrong_pin).unwrap_err(), "Incorrect setup PIN");
+        }
+        let locked = service.bootstrap("intruder", &pin).unwrap_err();
+        assert!(locked.contains("restart"), "{}", locked);
+        assert!(!service.is_trusted("intruder"));
+    }
+
+    #[test]
+    fn test_resume_token_rotates_and_is_dropped_once_paired() {
+        let service = DiscoveryService::new(TrustStore::in_memory());
+        service.register_device("phone".to_string(), "phone".to_string());
+        let Ok(PairingStart::Bootstrap { resume_token: first }) = service.begin_pairing("phone") else {
+            panic!("first device should need the setup PIN");
+        };
+        assert_eq!(service.resume(&first).as_deref(), Some("phone"));
+        assert!(service.resume("forged").is_none());
+
+        // Each connection gets a new token and the old one stops working
+        let Ok(PairingStart::Bootstrap { resume_token: second }) = service.begin_pairing("phone") else {
+            panic!("first device should need the setup PIN");
+        };
+        assert!(service.resume(&first).is_none());
+        assert_eq!(service.resume(&second).as_deref(), Some("phone"));
+
+        let pin = service.bootstrap_pin().unwrap();
+        service.bootstrap("phone", &pin).unwrap();
+        assert!(service.resume(&second).is_none());
+    }
+
+    #[test]
+    fn test_devices_stay_listed_until_the_grace_period_ends() {
+        let service = DiscoveryService::new(TrustStore::in_memory());
+        service.register_device("phone".to_string(), "phone".to_string());
+        service.register_device("laptop".to_string(), "laptop".to_string());
+        service.mark_offline("phone");
+        assert!(service.prune_offline().is_empty());
+        let devices = service.get_nearby_devices();
+        assert_eq!(devices.len(), 2);
+        assert!(devices.iter().any(|device| device.id == "phone" && !device.online));
+
+        service.devices.write().unwrap().get_mut("phone").unwrap().last_seen -=
+            Duration::seconds(RECONNECT_GRACE_SECS);
+        assert_eq!(service.prune_offline(), ["phone"]);
+        let devices = service.get_nearby_devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, "laptop");
+    }
+}
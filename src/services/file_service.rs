@@ -1,17 +1,91 @@
 use std::{fs, io};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::fs::File as TokioFile;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use tokio::fs::{File as TokioFile, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tempfile::NamedTempFile;
-use futures_util::StreamExt;
+use futures_util::{Stream, StreamExt};
 use actix_multipart::Field;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use crate::config::StorageConfig;
 use crate::repositories::file_repository::FileRepository;
 use crate::storage::file_store::FileStore;
-use crate::models::file::File;
+use crate::models::file::{File, FileList, FileListQuery, RetentionOptions};
+use crate::models::upload::UploadSession;
+use crate::models::usage::{DeviceUsage, QuotaLimits, StorageUsage};
+use crate::services::metrics_service::Metrics;
 use std::io::Write;
 
+/// How far a multipart upload's reservation is grown past its size each time it runs out
+const RESERVATION_STEP: u64 = 8 * 1024 * 1024;
+/// Superseded journal entries the reaper lets pile up before rewriting the journal
+const JOURNAL_COMPACT_THRESHOLD: usize = 1000;
+
 pub struct FileService {
     store: Arc<FileStore>,
     repository: Arc<Mutex<FileRepository>>, 
+    uploads: Mutex<HashMap<String, UploadSession>>,
+    /// Bytes held against quotas by uploads not yet registered as files, keyed by upload
+    reservations: Mutex<HashMap<String, Reserved>>,
+    upload_dir: PathBuf,
+    /// Multipart uploads are spooled here, on the same filesystem as the blobs they become
+    temp_dir: PathBuf,
+    default_ttl: Option<Duration>,
+    max_ttl: Option<Duration>,
+    /// How long an upload session may go without a chunk before it is discarded
+    upload_idle: Option<Duration>,
+    limits: QuotaLimits,
+    /// Set once shutdown starts; no new uploads are accepted after that
+    draining: AtomicBool,
+    /// Requests currently writing upload data
+    active_uploads: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+struct Reserved {
+    owner: Option<String>,
+    bytes: u64,
+}
+
+/// Releases an upload's quota reservation when dropped.
+struct ReservationGuard<'a> {
+    reservations: &'a Mutex<HashMap<String, Reserved>>,
+    key: String,
+}
+
+impl Drop for ReservationGuard<'_> {
+    fn drop(&mut self) {
+        self.reservations.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Counts a chunk request as writing to its upload session for as long as it is alive.
+struct WriterGuard<'a> {
+    uploads: &'a Mutex<HashMap<String, UploadSession>>,
+    id: String,
+}
+
+impl Drop for WriterGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.uploads.lock().unwrap().get_mut(&self.id) {
+            session.writers -= 1;
+        }
+    }
+}
+
+/// Counts a request as an in-flight upload for as long as it is alive.
+struct UploadGuard<'a>(&'a AtomicUsize);
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl FileService {
@@ -19,86 +93,1059 @@ impl FileService {
         fs::create_dir_all(&storage_path)?;
 
         let store = FileStore::new(storage_path.clone())?;
+        let orphans = store.orphaned_blobs().len();
+        if orphans > 0 {
+            log::warn!(
+                "{} blob(s) in {} have no metadata record and cannot be served",
+                orphans,
+                storage_path.display()
+            );
+        }
+
+        // Upload sessions live in memory, so parts left over from a previous run can never be resumed
+        let upload_dir = storage_path.join("uploads");
+        let temp_dir = storage_path.join("tmp");
+        for dir in [&upload_dir, &temp_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+            fs::create_dir_all(dir)?;
+        }
         
+        // Without a config, retention follows the configuration defaults
+        let defaults = StorageConfig::default();
         Ok(Self {
             store: Arc::new(store),
             repository: Arc::new(Mutex::new(FileRepository::new())),
+            uploads: Mutex::new(HashMap::new()),
+            reservations: Mutex::new(HashMap::new()),
+            upload_dir,
+            temp_dir,
+            default_ttl: defaults.default_ttl(),
+            max_ttl: defaults.max_ttl(),
+            upload_idle: defaults.upload_idle(),
+            limits: QuotaLimits::default(),
+            draining: AtomicBool::new(false),
+            active_uploads: AtomicUsize::new(0),
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
-    pub async fn save_file(&self, mut field: Field) -> io::Result<File> {
+    pub fn from_config(config: &StorageConfig) -> io::Result<Self> {
+        let mut service = Self::new(config.path.clone())?.with_limits(config.limits());
+        service.default_ttl = config.default_ttl();
+        service.max_ttl = config.max_ttl();
+        service.upload_idle = config.upload_idle();
+        Ok(service)
+    }
+
+    pub fn with_limits(mut self, limits: QuotaLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Whether new uploads are being refused because shutdown has started.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Checks that a file can be created and removed in the storage directory.
+    pub fn check_writable(&self) -> io::Result<()> {
+        let probe = NamedTempFile::new_in(self.store.generate_storage_path())?;
+        probe.close()
+    }
+
+    /// Current usage, counting upload sessions by their declared size.
+    pub fn usage(&self) -> StorageUsage {
+        self.usage_with(&self.reservations.lock().unwrap())
+    }
+
+    fn usage_with(&self, reservations: &HashMap<String, Reserved>) -> StorageUsage {
+        let mut by_owner = self.store.usage_by_owner();
+        let used_bytes = by_owner.values().map(|(bytes, _)| bytes).sum();
+        let file_count = by_owner.values().map(|(_, count)| count).sum();
+
+        let mut reserved_bytes = 0;
+        for reserved in reservations.values() {
+            reserved_bytes += reserved.bytes;
+            by_owner.entry(reserved.owner.clone()).or_default().0 += reserved.bytes;
+        }
+
+        let mut devices: Vec<DeviceUsage> = by_owner
+            .into_iter()
+            .map(|(device_id, (used_bytes, file_count))| DeviceUsage {
+                device_id,
+                used_bytes,
+                file_count,
+            })
+            .collect();
+        devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+        StorageUsage {
+            used_bytes,
+            reserved_bytes,
+            file_count,
+            limits: self.limits,
+            devices,
+        }
+    }
+
+    /// Checks that `owner` may store an upload of `size` bytes, returning the most bytes the
+    /// upload may grow to before a limit is hit, or `None` when nothing limits it.
+    pub fn upload_allowance(&self, owner: Option<&str>, size: u64) -> io::Result<Option<u64>> {
+        self.allowance_with(&self.reservations.lock().unwrap(), owner, size)
+    }
+
+    fn allowance_with(
+        &self,
+        reservations: &HashMap<String, Reserved>,
+        owner: Option<&str>,
+        size: u64,
+    ) -> io::Result<Option<u64>> {
+        let QuotaLimits {
+            max_file_size,
+            global_quota,
+            device_quota,
+        } = self.limits;
+
+        if let Some(max) = max_file_size.filter(|max| size > *max) {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("File of {} bytes exceeds the {} byte limit", size, max),
+            ));
+        }
+        if global_quota.is_none() && device_quota.is_none() {
+            return Ok(max_file_size);
+        }
+
+        let usage = self.usage_with(reservations);
+        let mut allowance = max_file_size;
+        if let Some(quota) = global_quota {
+            let used = usage.used_bytes + usage.reserved_bytes;
+            allowance = Self::fit_quota(allowance, used, quota, size, "Storage")?;
+        }
+        if let Some(quota) = device_quota {
+            let used = usage
+                .devices
+                .iter()
+                .find(|device| device.device_id.as_deref() == owner)
+                .map_or(0, |device| device.used_bytes);
+            allowance = Self::fit_quota(allowance, used, quota, size, "Device")?;
+        }
+        Ok(allowance)
+    }
+
+    /// Holds `size` bytes of quota for the upload `key`, replacing what it held before. The check
+    /// and the reservation happen under one lock, so concurrent uploads cannot both take the
+    /// last of a quota.
+    fn reserve(&self, key: &str, owner: Option<&str>, size: u64) -> io::Result<()> {
+        let mut reservations = self.reservations.lock().unwrap();
+        let previous = reservations.remove(key);
+        match self.allowance_with(&reservations, owner, size) {
+            Ok(_) => {
+                reservations.insert(key.to_string(), Reserved {
+                    owner: owner.map(str::to_string),
+                    bytes: size,
+                });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(previous) = previous {
+                    reservations.insert(key.to_string(), previous);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Grows a multipart upload's reservation to cover `size` bytes, a step ahead where quotas
+    /// leave room so that not every chunk needs a check. Returns the new reservation.
+    fn grow_reservation(&self, key: &str, owner: Option<&str>, size: u64, reserved: u64) -> io::Result<u64> {
+        let ahead = size.max(reserved.saturating_add(RESERVATION_STEP));
+        let ahead = self.limits.max_file_size.map_or(ahead, |max| ahead.min(max).max(size));
+        match self.reserve(key, owner, ahead) {
+            Ok(()) => Ok(ahead),
+            Err(_) if ahead > size => self.reserve(key, owner, size).map(|_| size),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes over the reservation held for `key`, releasing it when the guard is dropped.
+    fn reservation(&self, key: &str) -> ReservationGuard<'_> {
+        ReservationGuard {
+            reservations: &self.reservations,
+            key: key.to_string(),
+        }
+    }
+
+    fn fit_quota(allowance: Option<u64>, used: u64, quota: u64, size: u64, scope: &str) -> io::Result<Option<u64>> {
+        let free = quota.saturating_sub(used);
+        if size > free {
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!(
+                    "{} quota exceeded: {} of {} bytes in use, {} more requested",
+                    scope, used, quota, size
+                ),
+            ));
+        }
+        Ok(Some(allowance.map_or(free, |allowance| allowance.min(free))))
+    }
+
+    /// Spawns the background task that deletes expired files every `every`.
+    pub fn start_reaper(self: Arc<Self>, every: Duration) {
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                self.reap_expired();
+            }
+        });
+    }
+
+    /// Stops accepting new uploads. Chunks for upload sessions that already exist are still taken.
+    pub fn begin_shutdown(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for in-flight uploads to finish, returning `false` if some were still running
+    /// when `grace` ran out.
+    pub async fn drain(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        loop {
+            let active = self.active_uploads.load(Ordering::SeqCst);
+            if active == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                log::warn!("{} upload(s) still running after the shutdown grace period", active);
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Deletes unfinished upload session parts and spooled multipart uploads, returning how
+    /// many files were removed and how many could not be.
+    ///
+    /// A file that cannot be removed is logged and skipped. An error is only returned when one of
+    /// the directories cannot be listed, after the other has still been cleaned.
+    pub fn remove_partial_files(&self) -> io::Result<(usize, usize)> {
+        self.uploads.lock().unwrap().clear();
+        self.reservations.lock().unwrap().clear();
+
+        let mut removed = 0;
+        let mut failed = 0;
+        let mut unreadable = None;
+        for dir in [&self.upload_dir, &self.temp_dir] {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("Failed to list {}: {}", dir.display(), e);
+                    unreadable.get_or_insert(e);
+                    continue;
+                }
+            };
+            for entry in entries {
+                let removal = entry.and_then(|entry| {
+                    let path = entry.path();
+                    fs::remove_file(&path)
+                        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+                });
+                match removal {
+                    Ok(()) => removed += 1,
+                    Err(e) => {
+                        log::warn!("Failed to remove partial upload file {}", e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+
+        match unreadable {
+            Some(e) => Err(e),
+            None => Ok((removed, failed)),
+        }
+    }
+
+    /// Tracks a new upload, refusing it once shutdown has started.
+    fn start_upload(&self) -> io::Result<UploadGuard<'_>> {
+        if self.is_draining() {
+            return Err(io::Error::new(io::ErrorKind::ResourceBusy, "Server is shutting down"));
+        }
+        Ok(self.track_upload())
+    }
+
+    fn track_upload(&self) -> UploadGuard<'_> {
+        self.active_uploads.fetch_add(1, Ordering::SeqCst);
+        UploadGuard(&self.active_uploads)
+    }
+
+    /// Deletes every file past its TTL or download allowance, returning what was removed,
+    /// discards upload sessions that have been idle too long, and compacts the metadata journal
+    /// once enough of it is dead.
+    pub fn reap_expired(&self) -> Vec<File> {
+        self.reap_idle_uploads();
+
+        let mut removed = Vec::new();
+        for file in self.store.expired_files(Utc::now()) {
+            match self.delete_file(&file.id) {
+                Ok(file) => {
+                    log::info!(
+                        "Reaped expired file {} ({}, {} bytes, {} downloads)",
+                        file.id,
+                        file.filename,
+                        file.size,
+                        file.download_count
+                    );
+                    removed.push(file);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Failed to reap expired file {}: {}", file.id, e),
+            }
+        }
+        if !removed.is_empty() {
+            log::info!("Reaper removed {} expired file(s)", removed.len());
+        }
+        if let Err(e) = self.store.compact_journal(JOURNAL_COMPACT_THRESHOLD) {
+            log::error!("Failed to compact the metadata journal: {}", e);
+        }
+        removed
+    }
+
+    /// Drops upload sessions that received no chunk for the idle timeout, deleting their part
+    /// files and releasing their quota reservations. Sessions being written to are kept.
+    fn reap_idle_uploads(&self) {
+        let Some(idle) = self.upload_idle.and_then(|idle| chrono::Duration::from_std(idle).ok()) else {
+            return;
+        };
+        let cutoff = Utc::now() - idle;
+        let idle_sessions: Vec<UploadSession> = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let ids: Vec<String> = uploads
+                .values()
+                .filter(|session| session.writers == 0 && session.updated_at < cutoff)
+                .map(|session| session.id.clone())
+                .collect();
+            ids.iter().filter_map(|id| uploads.remove(id)).collect()
+        };
+
+        for session in idle_sessions {
+            drop(self.reservation(&session.id));
+            match fs::remove_file(&session.part_path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => log::warn!("Failed to remove part file of upload session {}: {}", session.id, e),
+            }
+            log::info!("Discarded idle upload session {} ({})", session.id, session.filename);
+        }
+    }
+
+    fn validate_retention(&self, retention: &RetentionOptions) -> io::Result<()> {
+        if retention.ttl_secs == Some(0) || retention.max_downloads == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ttl_secs and max_downloads must be greater than zero",
+            ));
+        }
+        if let (Some(ttl), Some(max)) = (retention.ttl_secs, self.max_ttl) {
+            if ttl > max.as_secs() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ttl_secs must be at most {}", max.as_secs()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the expiry and download allowance, failing rather than keeping a file forever when
+    /// its TTL cannot be represented as a timestamp.
+    fn apply_retention(&self, mut file: File, retention: &RetentionOptions) -> io::Result<File> {
+        let ttl = retention.ttl_secs.map(Duration::from_secs).or(self.default_ttl);
+        file.expires_at = ttl
+            .map(|ttl| {
+                chrono::Duration::from_std(ttl)
+                    .ok()
+                    .and_then(|ttl| file.uploaded_at.checked_add_signed(ttl))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ttl_secs is out of range"))
+            })
+            .transpose()?;
+        file.max_downloads = retention.max_downloads;
+        Ok(file)
+    }
+
+    /// Counts a download, deleting the file once it reaches its download allowance. Fails with
+    /// `NotFound` when the file has expired or its allowance is already used up.
+    ///
+    /// The last allowed download unlinks the blob, so call this only once the blob is open (see
+    /// `read_file`) and serve the whole response, every range included, from that handle.
+    pub fn record_download(&self, id: &str) -> io::Result<File> {
+        // The store holds the authoritative count, so drop the cached copy rather than racing it
+        self.repository.lock().unwrap().remove(id);
+        let now = Utc::now();
+        let file = self.store.record_download(id, now)?;
+
+        if file.is_expired(now) {
+            log::info!(
+                "File {} reached its download limit of {}, deleted",
+                id,
+                file.download_count
+            );
+        }
+        Ok(file)
+    }
+
+    /// Streams a multipart field into storage, hashing it on the way.
+    ///
+    /// When `expected_sha256` is given, the upload is discarded unless its digest matches.
+    /// `declared_size` is reserved against quotas before anything is read, and the reservation
+    /// grows as the upload does; it is aborted as soon as it outgrows the size limit or `owner`'s
+    /// remaining quota. `on_progress` is called with the size of each piece of the file as it
+    /// arrives.
+    pub async fn save_file(
+        &self,
+        mut field: Field,
+        declared_size: Option<u64>,
+        expected_sha256: Option<&str>,
+        retention: &RetentionOptions,
+        owner: Option<&str>,
+        mut on_progress: impl FnMut(u64),
+    ) -> io::Result<File> {
+        let _guard = self.start_upload()?;
+        let expected_sha256 = expected_sha256.map(normalize_sha256).transpose()?;
+        self.validate_retention(retention)?;
+        let reservation = self.reservation(&uuid::Uuid::new_v4().to_string());
+        let mut reserved = declared_size.unwrap_or(0);
+        self.reserve(&reservation.key, owner, reserved)?;
+
         // Get filename from field
         let filename = field
             .content_disposition()
             .get_filename()
-            .map(|f| sanitize_filename::sanitize(f))
+            .map(sanitize_filename::sanitize)
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No filename provided"))?;
 
         // Create temporary file
-        let temp_file = NamedTempFile::new()?;
+        let temp_file = NamedTempFile::new_in(&self.temp_dir)?;
         let mut writer = std::io::BufWriter::new(&temp_file);
+        let mut hasher = Sha256::new();
         let mut size = 0u64;
 
         // Stream to temporary file
         while let Some(chunk) = field.next().await {
-            let data = chunk.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let data = chunk.map_err(|e| io::Error::other(e.to_string()))?;
             size += data.len() as u64;
+            self.metrics.bytes_received.add(data.len() as u64);
+            if size > reserved {
+                reserved = self.grow_reservation(&reservation.key, owner, size, reserved)?;
+            }
+            hasher.update(&data);
             writer.write_all(&data)?;
+            on_progress(data.len() as u64);
         }
 
         // Ensure writer is flushed and dropped before moving temp_file
         writer.flush()?;
         drop(writer);
 
+        let sha256 = format!("{:x}", hasher.finalize());
+        verify_sha256(&sha256, expected_sha256.as_deref())?;
+
         // Generate final path
         let file_id = uuid::Uuid::new_v4().to_string();
         let final_path = self.store.generate_file_path(&file_id);
 
+        // Create file record before persisting, so a bad TTL leaves no blob behind
+        let file = File::new(filename, size, final_path.clone())
+            .with_sha256(sha256)
+            .with_owner(owner.map(str::to_string));
+        let file = self.apply_retention(file, retention)?;
+
         // Persist file
         temp_file.persist(&final_path)?;
+        self.register_file(file)
+    }
 
-        // Create file record
-        let file = File::new(filename, size, final_path);
-
+    fn register_file(&self, file: File) -> io::Result<File> {
         // Save to the in-memory repository for caching
         self.repository.lock().unwrap().save(file.clone());
 
         // Persist file metadata to the FileStore
         self.store.add_file(file.clone())?;
 
+        self.metrics.record_upload(file.size);
         Ok(file)
     }
 
-    pub async fn read_file(&self, id: &str) -> io::Result<(File, TokioFile)> {
-        // First try cache
-        if let Some(file) = self.repository.lock().unwrap().get(id) {
-            match TokioFile::open(&file.file_path).await {
-                Ok(file_handle) => {
-                    log::info!("File found and opened: {}", id);
-                    return Ok((file, file_handle));
-                }
-                Err(e) => {
-                    log::error!("Error opening file {}: {}", id, e);
-                    return Err(e);
+    pub async fn create_upload_session(
+        &self,
+        filename: &str,
+        size: u64,
+        expected_sha256: Option<&str>,
+        retention: RetentionOptions,
+        owner: Option<&str>,
+    ) -> io::Result<UploadSession> {
+        let _guard = self.start_upload()?;
+        self.validate_retention(&retention)?;
+        let filename = sanitize_filename::sanitize(filename);
+        if filename.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No filename provided"));
+        }
+        let expected_sha256 = expected_sha256.map(normalize_sha256).transpose()?;
+
+        let mut session = UploadSession::new(filename, size, &self.upload_dir);
+        session.sha256 = expected_sha256;
+        session.retention = retention;
+        session.owner = owner.map(str::to_string);
+
+        // Sessions count against quotas by their declared size from the moment they are created
+        // until they are finalized or cancelled
+        self.reserve(&session.id, owner, size)?;
+
+        // Preallocate the part file so chunks can land at any offset
+        let preallocated: io::Result<()> = async {
+            let part = TokioFile::create(&session.part_path).await?;
+            part.set_len(size).await
+        }
+        .await;
+        if let Err(e) = preallocated {
+            drop(self.reservation(&session.id));
+            return Err(e);
+        }
+
+        log::info!("Upload session {} created for {} ({} bytes)", session.id, session.filename, size);
+        self.uploads.lock().unwrap().insert(session.id.clone(), session.clone());
+
+        Ok(session)
+    }
+
+    pub fn get_upload_session(&self, id: &str) -> io::Result<UploadSession> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Upload session not found"))
+    }
+
+    /// Registers a chunk request as writing to session `id`, so the session cannot be finalized
+    /// or cancelled under it.
+    fn start_writing(&self, id: &str) -> io::Result<(UploadSession, WriterGuard<'_>)> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let session = uploads
+            .get_mut(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Upload session not found"))?;
+        session.writers += 1;
+        session.updated_at = Utc::now();
+        let guard = WriterGuard {
+            uploads: &self.uploads,
+            id: id.to_string(),
+        };
+        Ok((session.clone(), guard))
+    }
+
+    /// Takes session `id` out of the map unless a chunk is still being written to it.
+    fn take_session(uploads: &mut HashMap<String, UploadSession>, id: &str) -> io::Result<UploadSession> {
+        let session = uploads
+            .get(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Upload session not found"))?;
+        if session.writers > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "A chunk is still being written to this upload",
+            ));
+        }
+        Ok(uploads.remove(id).unwrap())
+    }
+
+    /// Writes a chunk of an upload session starting at `offset`.
+    ///
+    /// Bytes that reach disk are recorded even if the stream fails part-way, so the client can
+    /// resume from whatever the session reports as received.
+    pub async fn write_upload_chunk<S, E>(&self, id: &str, offset: u64, mut body: S) -> io::Result<UploadSession>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let _guard = self.track_upload();
+        let (session, _writer) = self.start_writing(id)?;
+        if offset > session.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Offset {} is beyond the upload size {}", offset, session.size),
+            ));
+        }
+
+        let mut part = OpenOptions::new().write(true).open(&session.part_path).await?;
+        part.seek(SeekFrom::Start(offset)).await?;
+
+        let mut written = 0u64;
+        let streamed: io::Result<()> = async {
+            while let Some(chunk) = body.next().await {
+                let data = chunk.map_err(|e| io::Error::other(e.to_string()))?;
+                if offset + written + data.len() as u64 > session.size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Chunk extends past the declared upload size",
+                    ));
                 }
+                part.write_all(&data).await?;
+                written += data.len() as u64;
+                self.metrics.bytes_received.add(data.len() as u64);
             }
+            Ok(())
         }
+        .await;
+        part.flush().await?;
 
-        // If not in cache, check storage
-        if let Some(file) = self.store.get_file(id) {
-            match TokioFile::open(&file.file_path).await {
-                Ok(file_handle) => {
-                    log::info!("File found in storage: {}", id);
-                    return Ok((file, file_handle));
-                }
-                Err(e) => {
-                    log::error!("Error opening file from storage {}: {}", id, e);
-                    return Err(e);
-                }
+        let session = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let session = uploads
+                .get_mut(id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Upload session not found"))?;
+            session.mark_received(offset, offset + written);
+            session.updated_at = Utc::now();
+            session.clone()
+        };
+
+        streamed.map(|_| session)
+    }
+
+    /// Moves a fully received upload session into storage and registers it as a file.
+    ///
+    /// Refused with `WouldBlock` while a chunk is still being written to the session.
+    pub async fn finalize_upload(&self, id: &str) -> io::Result<File> {
+        let _guard = self.track_upload();
+        let session = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let session = uploads
+                .get(id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Upload session not found"))?;
+            if session.writers == 0 && !session.is_complete() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Upload incomplete: {} of {} bytes received",
+                        session.offset(),
+                        session.size
+                    ),
+                ));
+            }
+            Self::take_session(&mut uploads, id)?
+        };
+        // The session keeps its quota until its file is registered or it is discarded
+        let _reservation = self.reservation(id);
+
+        // Chunks may have arrived in any order, so hash the assembled part file
+        let sha256 = hash_file(&session.part_path).await?;
+        if let Err(e) = verify_sha256(&sha256, session.sha256.as_deref()) {
+            tokio::fs::remove_file(&session.part_path).await?;
+            log::warn!("Upload session {} discarded: {}", session.id, e);
+            return Err(e);
+        }
+
+        let file_id = uuid::Uuid::new_v4().to_string();
+        let final_path = self.store.generate_file_path(&file_id);
+        let file = File::new(session.filename, session.size, final_path.clone())
+            .with_sha256(sha256)
+            .with_owner(session.owner);
+        let file = match self.apply_retention(file, &session.retention) {
+            Ok(file) => file,
+            Err(e) => {
+                tokio::fs::remove_file(&session.part_path).await?;
+                return Err(e);
+            }
+        };
+        tokio::fs::rename(&session.part_path, &final_path).await?;
+
+        log::info!("Upload session {} finalized", session.id);
+        self.register_file(file)
+    }
+
+    /// Discards an upload session; refused with `WouldBlock` while a chunk is being written.
+    pub async fn cancel_upload(&self, id: &str) -> io::Result<()> {
+        let session = Self::take_session(&mut self.uploads.lock().unwrap(), id)?;
+        drop(self.reservation(id));
+
+        tokio::fs::remove_file(&session.part_path).await
+    }
+
+    pub fn list_files(&self, query: &FileListQuery) -> FileList {
+        self.store.list_files(query, Utc::now())
+    }
+
+    /// A file's record. Files past their TTL or download allowance are `NotFound`, as the reaper
+    /// may not have caught up with them yet.
+    pub fn get_file_info(&self, id: &str) -> io::Result<File> {
+        let cached = self.repository.lock().unwrap().get(id);
+        let file = cached
+            .or_else(|| self.store.get_file(id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+        if file.is_expired(Utc::now()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "File has expired"));
+        }
+        Ok(file)
+    }
+
+    pub fn delete_file(&self, id: &str) -> io::Result<File> {
+        self.repository.lock().unwrap().remove(id);
+        let file = self
+            .store
+            .remove_file(id)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+
+        log::info!("File deleted: {} ({})", id, file.filename);
+        Ok(file)
+    }
+
+    pub async fn read_file(&self, id: &str) -> io::Result<(File, TokioFile)> {
+        let file = match self.get_file_info(id) {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("File {} unavailable: {}", id, e);
+                return Err(e);
+            }
+        };
+
+        match TokioFile::open(&file.file_path).await {
+            Ok(file_handle) => {
+                log::info!("File found and opened: {}", id);
+                Ok((file, file_handle))
+            }
+            Err(e) => {
+                log::error!("Error opening file {}: {}", id, e);
+                Err(e)
             }
         }
+    }
+}
+
+/// Lower-cases a client-supplied hex SHA-256 digest, rejecting anything that is not one.
+fn normalize_sha256(digest: &str) -> io::Result<String> {
+    if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(digest.to_ascii_lowercase())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Expected SHA-256 digest must be 64 hex characters",
+        ))
+    }
+}
+
+fn verify_sha256(actual: &str, expected: Option<&str>) -> io::Result<()> {
+    match expected {
+        Some(expected) if expected != actual => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "SHA-256 mismatch: expected {}, received content hashes to {}",
+                expected, actual
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+async fn hash_file(path: &std::path::Path) -> io::Result<String> {
+    let mut file = TokioFile::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    async fn upload(service: &FileService, retention: RetentionOptions) -> File {
+        let session = service
+            .create_upload_session("note.txt", 4, None, retention, None)
+            .await
+            .unwrap();
+        let body = stream::iter([Ok::<_, io::Error>(Bytes::from_static(b"note"))]);
+        service.write_upload_chunk(&session.id, 0, body).await.unwrap();
+        service.finalize_upload(&session.id).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_file_deleted_after_max_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap();
+        let retention = RetentionOptions {
+            ttl_secs: None,
+            max_downloads: Some(2),
+        };
+        let file = upload(&service, retention).await;
+        // Files without a TTL of their own expire after the configured default
+        let default_ttl = StorageConfig::default().default_ttl().unwrap();
+        let lifetime = file.expires_at.unwrap() - file.uploaded_at;
+        assert_eq!(lifetime.num_seconds(), default_ttl.as_secs() as i64);
+
+        service.record_download(&file.id).unwrap();
+        assert_eq!(service.get_file_info(&file.id).unwrap().download_count, 1);
+
+        service.record_download(&file.id).unwrap();
+        assert!(service.get_file_info(&file.id).is_err());
+        assert!(!file.file_path.exists());
+        assert_eq!(service.record_download(&file.id).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[actix_rt::test]
+    async fn test_out_of_range_ttl_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap();
+        let too_long = RetentionOptions {
+            ttl_secs: Some(u64::MAX),
+            max_downloads: None,
+        };
+        let refused = service
+            .create_upload_session("note.txt", 4, None, too_long.clone(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::InvalidInput);
+
+        // Without a maximum the TTL still has to fit in a timestamp
+        let config = StorageConfig {
+            path: dir.path().join("unbounded"),
+            max_ttl_secs: 0,
+            ..StorageConfig::default()
+        };
+        let service = FileService::from_config(&config).unwrap();
+        let session = service
+            .create_upload_session("note.txt", 4, None, too_long, None)
+            .await
+            .unwrap();
+        let body = stream::iter([Ok::<_, io::Error>(Bytes::from_static(b"note"))]);
+        service.write_upload_chunk(&session.id, 0, body).await.unwrap();
+        let refused = service.finalize_upload(&session.id).await.unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::InvalidInput);
+        assert!(!session.part_path.exists());
+        assert_eq!(service.list_files(&FileListQuery::default()).total, 0);
+    }
+
+    /// A multipart body whose bytes are fed through the returned sender.
+    fn multipart_field() -> (
+        futures::channel::mpsc::UnboundedSender<Result<Bytes, actix_web::error::PayloadError>>,
+        actix_multipart::Multipart,
+    ) {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut headers = actix_web::http::header::HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::CONTENT_TYPE,
+            "multipart/form-data; boundary=B".parse().unwrap(),
+        );
+        tx.unbounded_send(Ok(Bytes::from_static(
+            b"--B\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n\r\n",
+        )))
+        .unwrap();
+        (tx, actix_multipart::Multipart::new(&headers, rx))
+    }
+
+    #[actix_rt::test]
+    async fn test_in_flight_uploads_hold_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = QuotaLimits {
+            max_file_size: None,
+            global_quota: None,
+            device_quota: Some(100),
+        };
+        let service = Arc::new(FileService::new(dir.path().to_path_buf()).unwrap().with_limits(limits));
+
+        // The first upload announces no size and reserves as it streams
+        let (tx, mut multipart) = multipart_field();
+        let field = multipart.next().await.unwrap().unwrap();
+        let first = actix_rt::spawn({
+            let service = Arc::clone(&service);
+            async move {
+                let _multipart = multipart;
+                service
+                    .save_file(field, None, None, &RetentionOptions::default(), Some("a"), |_| {})
+                    .await
+            }
+        });
+        tx.unbounded_send(Ok(Bytes::from(vec![b'x'; 60]))).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(service.usage().reserved_bytes, 60);
+
+        // A concurrent upload cannot take the quota the first one is still using
+        let (_tx, mut multipart) = multipart_field();
+        let field = multipart.next().await.unwrap().unwrap();
+        let refused = service
+            .save_file(field, Some(60), None, &RetentionOptions::default(), Some("a"), |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::QuotaExceeded);
+        assert!(service.upload_allowance(Some("a"), 60).is_err());
+        assert!(service.upload_allowance(Some("b"), 60).is_ok());
+
+        tx.unbounded_send(Ok(Bytes::from_static(b"\r\n--B--\r\n"))).unwrap();
+        drop(tx);
+        let file = first.await.unwrap().unwrap();
+        assert_eq!(file.size, 60);
+        let usage = service.usage();
+        assert_eq!((usage.used_bytes, usage.reserved_bytes), (60, 0));
+
+        // Session reservations are released when the session is cancelled
+        let session = service
+            .create_upload_session("b.bin", 40, None, RetentionOptions::default(), Some("a"))
+            .await
+            .unwrap();
+        assert!(service.upload_allowance(Some("a"), 1).is_err());
+        service.cancel_upload(&session.id).await.unwrap();
+        assert_eq!(service.usage().reserved_bytes, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_idle_upload_sessions_release_their_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap().with_limits(QuotaLimits {
+            device_quota: Some(50),
+            ..QuotaLimits::default()
+        });
+        let abandoned = service
+            .create_upload_session("abandoned.bin", 40, None, RetentionOptions::default(), Some("a"))
+            .await
+            .unwrap();
+        let active = service
+            .create_upload_session("active.bin", 4, None, RetentionOptions::default(), Some("a"))
+            .await
+            .unwrap();
+        assert!(service.upload_allowance(Some("a"), 10).is_err());
+
+        let idle = chrono::Duration::from_std(service.upload_idle.unwrap()).unwrap();
+        for session in service.uploads.lock().unwrap().values_mut() {
+            session.updated_at -= idle;
+        }
+        // A chunk keeps its session alive
+        let body = stream::iter([Ok::<_, io::Error>(Bytes::from_static(b"ab"))]);
+        service.write_upload_chunk(&active.id, 0, body).await.unwrap();
+
+        service.reap_expired();
+        assert_eq!(service.get_upload_session(&abandoned.id).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!abandoned.part_path.exists());
+        assert_eq!(service.usage().reserved_bytes, 4);
+        service.upload_allowance(Some("a"), 40).unwrap();
+        assert!(service.get_upload_session(&active.id).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_session_busy_while_a_chunk_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap();
+        let session = service
+            .create_upload_session("slow.bin", 4, None, RetentionOptions::default(), None)
+            .await
+            .unwrap();
+
+        let (tx, rx) = futures::channel::mpsc::unbounded::<Result<Bytes, io::Error>>();
+        let write = service.write_upload_chunk(&session.id, 0, rx);
+        let race = async {
+            while service.get_upload_session(&session.id).unwrap().writers == 0 {
+                tokio::task::yield_now().await;
+            }
+            tx.unbounded_send(Ok(Bytes::from_static(b"slow"))).unwrap();
+
+            let finalize = service.finalize_upload(&session.id).await.unwrap_err();
+            assert_eq!(finalize.kind(), io::ErrorKind::WouldBlock);
+            let cancel = service.cancel_upload(&session.id).await.unwrap_err();
+            assert_eq!(cancel.kind(), io::ErrorKind::WouldBlock);
+            tx.close_channel();
+        };
+        let (written, ()) = futures::join!(write, race);
+        assert!(written.unwrap().is_complete());
+
+        assert_eq!(service.get_upload_session(&session.id).unwrap().writers, 0);
+        let file = service.finalize_upload(&session.id).await.unwrap();
+        assert_eq!(file.size, 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_shutdown_drains_uploads_and_removes_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap();
+        let session = service
+            .create_upload_session("big.bin", 8, None, RetentionOptions::default(), None)
+            .await
+            .unwrap();
+
+        let in_flight = service.track_upload();
+        service.begin_shutdown();
+        let refused = service
+            .create_upload_session("late.bin", 8, None, RetentionOptions::default(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ResourceBusy);
+
+        // Sessions that already exist may keep sending chunks during the grace period
+        let body = stream::iter([Ok::<_, io::Error>(Bytes::from_static(b"half"))]);
+        service.write_upload_chunk(&session.id, 0, body).await.unwrap();
+
+        assert!(!service.drain(Duration::from_millis(150)).await);
+        drop(in_flight);
+        assert!(service.drain(Duration::from_millis(150)).await);
+
+        assert_eq!(service.remove_partial_files().unwrap(), (1, 0));
+        assert!(!session.part_path.exists());
+        assert!(service.get_upload_session(&session.id).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_partial_file_cleanup_skips_what_it_cannot_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap();
+        let session = service
+            .create_upload_session("a.bin", 8, None, RetentionOptions::default(), None)
+            .await
+            .unwrap();
+        // A directory cannot be removed with remove_file
+        fs::create_dir(service.upload_dir.join("stuck.part")).unwrap();
+
+        assert_eq!(service.remove_partial_files().unwrap(), (1, 1));
+        assert!(!session.part_path.exists());
+
+        let session = service
+            .create_upload_session("b.bin", 8, None, RetentionOptions::default(), None)
+            .await
+            .unwrap();
+        fs::remove_dir(&service.temp_dir).unwrap();
+        assert!(service.remove_partial_files().is_err());
+        assert!(!session.part_path.exists());
+    }
+
+    #[actix_rt::test]
+    async fn test_reaper_removes_expired_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = FileService::new(dir.path().to_path_buf()).unwrap();
+        let kept = upload(&service, RetentionOptions::default()).await;
+        let mut expired = upload(&service, RetentionOptions::default()).await;
+
+        expired.expires_at = Some(Utc::now() - chrono::Duration::seconds(1));
+        service.register_file(expired.clone()).unwrap();
+        assert!(service.read_file(&expired.id).await.is_err());
 
-        log::error!("File not found: {}", id);
-        Err(io::Error::new(io::ErrorKind::NotFound, "File not found"))
+        let removed = service.reap_expired();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id, expired.id);
+        assert!(!expired.file_path.exists());
+        assert!(service.get_file_info(&kept.id).is_ok());
     }
 }
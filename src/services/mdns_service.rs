@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+
+/// DNS-SD service type the server is announced under
+pub const SERVICE_TYPE: &str = "_windrop._tcp.local.";
+/// Version of the HTTP/WebSocket API, advertised so clients can skip servers they cannot talk to
+pub const API_VERSION: &str = "1";
+
+/// What the server puts in its TXT record.
+pub struct Advertisement<'a> {
+    pub name: &'a str,
+    pub addr: SocketAddr,
+    pub tls_fingerprint: Option<&'a str>,
+}
+
+/// Keeps the server announced on the LAN until stopped.
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvertiser {
+    /// Registers the server. A server bound to all interfaces is announced with every address
+    /// the host has, following address changes; otherwise only the bound address is announced.
+    pub fn start(advertisement: &Advertisement) -> io::Result<Self> {
+        let daemon = new_daemon()?;
+
+        let mut properties = HashMap::from([
+            ("api_version".to_string(), API_VERSION.to_string()),
+            ("name".to_string(), advertisement.name.to_string()),
+            ("ws_path".to_string(), "/api/ws".to_string()),
+            ("tls".to_string(), if advertisement.tls_fingerprint.is_some() { "1" } else { "0" }.to_string()),
+        ]);
+        if let Some(fingerprint) = advertisement.tls_fingerprint {
+            properties.insert("fingerprint".to_string(), fingerprint.to_string());
+        }
+
+        let host_name = format!("{}.local.", dns_label(advertisement.name));
+        let ip = advertisement.addr.ip();
+        let addresses: Vec<IpAddr> = if ip.is_unspecified() { Vec::new() } else { vec![ip] };
+        let mut info = ServiceInfo::new(
+            SERVICE_TYPE,
+            advertisement.name,
+            &host_name,
+            addresses.as_slice(),
+            advertisement.addr.port(),
+            properties,
+        )
+        .map_err(mdns_error)?;
+        if ip.is_unspecified() {
+            info = info.enable_addr_auto();
+        }
+
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info).map_err(mdns_error)?;
+        log::info!("Advertising {} on the local network", fullname);
+
+        Ok(Self { daemon, fullname })
+    }
+
+    /// Sends a goodbye so browsers drop the server right away, then stops the responder.
+    pub fn stop(self) {
+        if let Ok(status) = self.daemon.unregister(&self.fullname) {
+            let _ = status.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// A server found by [`browse`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub api_version: Option<String>,
+    pub ws_path: Option<String>,
+    /// Certificate fingerprint to pin; `None` for servers that speak plain HTTP
+    pub tls_fingerprint: Option<String>,
+}
+
+/// Listens for `_windrop._tcp` announcements for `timeout` and returns every server resolved.
+pub fn browse(timeout: Duration) -> io::Result<Vec<DiscoveredServer>> {
+    let daemon = new_daemon()?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(mdns_error)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut servers: HashMap<String, DiscoveredServer> = HashMap::new();
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match events.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let txt = |key: &str| info.get_property_val_str(key).map(str::to_string);
+                let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+                addresses.sort();
+                let server = DiscoveredServer {
+                    name: txt("name").unwrap_or_else(|| info.get_fullname().to_string()),
+                    addresses,
+                    port: info.get_port(),
+                    api_version: txt("api_version"),
+                    ws_path: txt("ws_path"),
+                    tls_fingerprint: txt("fingerprint").filter(|_| txt("tls").as_deref() == Some("1")),
+                };
+                servers.insert(info.get_fullname().to_string(), server);
+            }
+            Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                servers.remove(&fullname);
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(servers.into_values().collect())
+}
+
+fn new_daemon() -> io::Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new().map_err(mdns_error)?;
+    // Loopback is off by default; it lets a client on the same host, or a test, see the server
+    daemon.enable_interface(IfKind::LoopbackV4).map_err(mdns_error)?;
+    Ok(daemon)
+}
+
+/// Reduces a display name to a valid host label, e.g. "Living Room PC" to "living-room-pc".
+fn dns_label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "windrop".to_string()
+    } else {
+        label.chars().take(63).collect()
+    }
+}
+
+fn mdns_error(e: mdns_sd::Error) -> io::Error {
+    io::Error::other(format!("mDNS: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browse_finds_advertised_server_on_loopback() {
+        let name = format!("windrop-test-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        let advertiser = MdnsAdvertiser::start(&Advertisement {
+            name: &name,
+            addr: "127.0.0.1:18080".parse().unwrap(),
+            tls_fingerprint: Some("AB:CD"),
+        })
+        .unwrap();
+
+        let servers = browse(Duration::from_secs(3)).unwrap();
+        advertiser.stop();
+
+        let server = servers
+            .iter()
+            .find(|server| server.name == name)
+            .expect("advertised server should be found");
+        assert_eq!(server.port, 18080);
+        assert!(server.addresses.contains(&"127.0.0.1".parse().unwrap()));
+        assert_eq!(server.api_version.as_deref(), Some(API_VERSION));
+        assert_eq!(server.tls_fingerprint.as_deref(), Some("AB:CD"));
+    }
+}
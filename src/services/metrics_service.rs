@@ -0,0 +1,220 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const UPLOAD_SIZE_BUCKETS: &[f64] = &[
+    1024.0,
+    65536.0,
+    1048576.0,
+    16777216.0,
+    268435456.0,
+    1073741824.0,
+    4294967296.0,
+];
+const DOWNLOAD_SECONDS_BUCKETS: &[f64] = &[0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0, 120.0];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct HistogramState {
+    /// Per-bucket, not cumulative, counts; the last slot is the `+Inf` overflow
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            state: Mutex::new(HistogramState {
+                buckets: vec![0; bounds.len() + 1],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().unwrap();
+        state.buckets[bucket] += 1;
+        state.sum += value;
+        state.count += 1;
+    }
+}
+
+/// A value read at scrape time rather than counted as events happen.
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: f64,
+}
+
+/// Process-wide counters and histograms, rendered for Prometheus by [`Metrics::render`].
+pub struct Metrics {
+    pub uploads: Counter,
+    pub upload_size: Histogram,
+    pub bytes_received: Counter,
+    pub downloads: Counter,
+    pub download_duration: Histogram,
+    pub bytes_sent: Counter,
+    pub rate_limited: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            uploads: Counter::default(),
+            upload_size: Histogram::new(UPLOAD_SIZE_BUCKETS),
+            bytes_received: Counter::default(),
+            downloads: Counter::default(),
+            download_duration: Histogram::new(DOWNLOAD_SECONDS_BUCKETS),
+            bytes_sent: Counter::default(),
+            rate_limited: Counter::default(),
+        }
+    }
+
+    /// Records a stored upload of `size` bytes.
+    pub fn record_upload(&self, size: u64) {
+        self.uploads.inc();
+        self.upload_size.observe(size as f64);
+    }
+
+    /// Starts timing a download; the duration is recorded when the returned timer is dropped,
+    /// i.e. once the response body has been sent or abandoned.
+    pub fn download_timer(self: &Arc<Self>) -> DownloadTimer {
+        self.downloads.inc();
+        DownloadTimer {
+            metrics: self.clone(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn render(&self, gauges: &[Gauge]) -> String {
+        let mut out = String::new();
+        counter(&mut out, "windrop_uploads_total", "Files stored by uploads", &self.uploads);
+        histogram(&mut out, "windrop_upload_size_bytes", "Size of stored uploads", &self.upload_size);
+        counter(
+            &mut out,
+            "windrop_received_bytes_total",
+            "Upload bytes received, including uploads that did not complete",
+            &self.bytes_received,
+        );
+        counter(&mut out, "windrop_downloads_total", "File downloads started", &self.downloads);
+        histogram(
+            &mut out,
+            "windrop_download_duration_seconds",
+            "Time taken to send a download response",
+            &self.download_duration,
+        );
+        counter(&mut out, "windrop_sent_bytes_total", "File bytes sent to downloaders", &self.bytes_sent);
+        counter(
+            &mut out,
+            "windrop_rate_limited_requests_total",
+            "Requests rejected by the rate limiter",
+            &self.rate_limited,
+        );
+        for gauge in gauges {
+            header(&mut out, gauge.name, gauge.help, "gauge");
+            let _ = writeln!(out, "{} {}", gauge.name, gauge.value);
+        }
+        out
+    }
+}
+
+pub struct DownloadTimer {
+    metrics: Arc<Metrics>,
+    started: Instant,
+}
+
+impl DownloadTimer {
+    /// Counts `bytes` of the response body as sent.
+    pub fn sent(&self, bytes: usize) {
+        self.metrics.bytes_sent.add(bytes as u64);
+    }
+}
+
+impl Drop for DownloadTimer {
+    fn drop(&mut self) {
+        self.metrics.download_duration.observe(self.started.elapsed().as_secs_f64());
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, help, "histogram");
+    let state = histogram.state.lock().unwrap();
+    let mut cumulative = 0;
+    for (bound, count) in histogram.bounds.iter().zip(&state.buckets) {
+        cumulative += count;
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+    let _ = writeln!(out, "{}_sum {}", name, state.sum);
+    let _ = writeln!(out, "{}_count {}", name, state.count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.record_upload(500);
+        metrics.record_upload(2_000_000);
+        metrics.rate_limited.inc();
+
+        let text = metrics.render(&[Gauge {
+            name: "windrop_websocket_sessions",
+            help: "Open WebSocket sessions",
+            value: 3.0,
+        }]);
+
+        assert!(text.contains("# TYPE windrop_uploads_total counter\nwindrop_uploads_total 2\n"));
+        assert!(text.contains("windrop_upload_size_bytes_bucket{le=\"1024\"} 1\n"));
+        assert!(text.contains("windrop_upload_size_bytes_bucket{le=\"1048576\"} 1\n"));
+        assert!(text.contains("windrop_upload_size_bytes_bucket{le=\"16777216\"} 2\n"));
+        assert!(text.contains("windrop_upload_size_bytes_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("windrop_upload_size_bytes_sum 2000500\n"));
+        assert!(text.contains("windrop_rate_limited_requests_total 1\n"));
+        assert!(text.contains("# TYPE windrop_websocket_sessions gauge\nwindrop_websocket_sessions 3\n"));
+    }
+}
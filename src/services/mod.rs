@@ -1,2 +1,4 @@
 pub mod file_service;
-pub mod discovery_service;
\ No newline at end of file
+pub mod discovery_service;
+pub mod mdns_service;
+pub mod metrics_service;
@@ -1,13 +1,16 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 use std::collections::HashMap;
 use std::fs;
 use std::io;
-use crate::models::file::File;
+use crate::models::file::{File, FileList, FileListQuery, FileSort, SortOrder};
+use super::journal::{Journal, JournalEntry};
 
 pub struct FileStore {
     storage_path: PathBuf,
     files: RwLock<HashMap<String, File>>,
+    journal: Journal,
+    orphans: Vec<PathBuf>,
 }
 
 impl FileStore {
@@ -15,37 +18,432 @@ impl FileStore {
         if !storage_path.exists() {
             fs::create_dir_all(&storage_path)?;
         }
-        
+
         if !storage_path.is_dir() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Storage path must be a directory"
             ));
         }
-        
+
+        let journal = Journal::open(&storage_path)?;
+        let files = Self::load(&storage_path, &journal)?;
+        let orphans = Self::find_orphans(&storage_path, &files)?;
+
+        // Rewrite the journal so it only holds the reconciled records
+        let entries: Vec<JournalEntry> = files.values().map(Self::journal_entry).collect();
+        journal.compact(&entries)?;
+
+        log::info!(
+            "Loaded {} file records from {}",
+            files.len(),
+            journal.path().display()
+        );
+
         Ok(Self {
             storage_path,
-            files: RwLock::new(HashMap::new()),
+            files: RwLock::new(files),
+            journal,
+            orphans,
         })
     }
 
+    /// Replays the journal, dropping records whose blob no longer exists on disk.
+    fn load(storage_path: &Path, journal: &Journal) -> io::Result<HashMap<String, File>> {
+        let mut files = HashMap::new();
+
+        for entry in journal.replay()? {
+            match entry {
+                JournalEntry::Put { mut file, blob } => {
+                    file.file_path = storage_path.join(blob);
+                    files.insert(file.id.clone(), file);
+                }
+                JournalEntry::Remove { id } => {
+                    files.remove(&id);
+                }
+                JournalEntry::Download { id, count } => {
+                    if let Some(file) = files.get_mut(&id) {
+                        file.download_count = count;
+                    }
+                }
+            }
+        }
+
+        files.retain(|id, file| {
+            let exists = file.file_path.is_file();
+            if !exists {
+                log::warn!(
+                    "Dropping record {} ({}): blob {} is missing",
+                    id,
+                    file.filename,
+                    file.file_path.display()
+                );
+            }
+            exists
+        });
+
+        Ok(files)
+    }
+
+    /// Lists blobs in the storage directory that no record points to.
+    ///
+    /// Blobs are named by UUID, so sidecar files such as the journal are never reported.
+    fn find_orphans(
+        storage_path: &Path,
+        files: &HashMap<String, File>,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut orphans = Vec::new();
+
+        for entry in fs::read_dir(storage_path)? {
+            let path = entry?.path();
+            let is_blob = path
+                .file_name()
+                .is_some_and(|name| uuid::Uuid::parse_str(&name.to_string_lossy()).is_ok());
+            if !path.is_file() || !is_blob {
+                continue;
+            }
+            if !files.values().any(|file| file.file_path == path) {
+                log::warn!("Orphan blob with no metadata record: {}", path.display());
+                orphans.push(path);
+            }
+        }
+
+        Ok(orphans)
+    }
+
+    fn journal_entry(file: &File) -> JournalEntry {
+        let blob = file
+            .file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        JournalEntry::Put {
+            file: file.clone(),
+            blob,
+        }
+    }
+
     pub fn add_file(&self, file: File) -> io::Result<()> {
+        // Appended under the lock, so a compaction cannot snapshot the records in between
         let mut files = self.files.write().map_err(|_| {
-            io::Error::new(io::ErrorKind::Other, "Failed to acquire write lock")
+            io::Error::other("Failed to acquire write lock")
         })?;
+        self.journal.append(&Self::journal_entry(&file))?;
         files.insert(file.id.clone(), file);
         Ok(())
     }
 
+    /// Rewrites the journal as a snapshot of the current records once at least `min_dead` of
+    /// its entries are superseded, and returns whether it did.
+    pub fn compact_journal(&self, min_dead: usize) -> io::Result<bool> {
+        // Every append happens under the write lock, so holding the read lock keeps them out
+        let files = self.files.read().map_err(|_| {
+            io::Error::other("Failed to acquire read lock")
+        })?;
+        let dead = self.journal.entry_count().saturating_sub(files.len());
+        if dead < min_dead {
+            return Ok(false);
+        }
+
+        let entries: Vec<JournalEntry> = files.values().map(Self::journal_entry).collect();
+        self.journal.compact(&entries)?;
+        log::info!(
+            "Compacted {} to {} records, dropping {} dead entries",
+            self.journal.path().display(),
+            entries.len(),
+            dead
+        );
+        Ok(true)
+    }
+
     pub fn get_file(&self, id: &str) -> Option<File> {
         self.files.read().ok()?.get(id).cloned()
     }
 
+    /// Records whose TTL has passed or whose download allowance is used up.
+    pub fn expired_files(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<File> {
+        self.files
+            .read()
+            .map(|files| files.values().filter(|file| file.is_expired(now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Bytes and file count per owning device.
+    pub fn usage_by_owner(&self) -> HashMap<Option<String>, (u64, usize)> {
+        let mut usage: HashMap<Option<String>, (u64, usize)> = HashMap::new();
+        if let Ok(files) = self.files.read() {
+            for file in files.values() {
+                let entry = usage.entry(file.owner.clone()).or_default();
+                entry.0 += file.size;
+                entry.1 += 1;
+            }
+        }
+        usage
+    }
+
+    /// Filters, sorts and paginates the stored records.
+    /// One page of the files matching `query`. Files past their TTL or download allowance at
+    /// `now` are left out, even before the reaper removes them.
+    pub fn list_files(&self, query: &FileListQuery, now: chrono::DateTime<chrono::Utc>) -> FileList {
+        let needle = query.filename.as_deref().map(str::to_lowercase);
+        let mut files: Vec<File> = self
+            .files
+            .read()
+            .map(|files| {
+                files
+                    .values()
+                    .filter(|file| !file.is_expired(now))
+                    .filter(|file| match &needle {
+                        Some(needle) => file.filename.to_lowercase().contains(needle),
+                        None => true,
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        files.sort_by(|a, b| {
+            let ordering = match query.sort {
+                FileSort::UploadedAt => a.uploaded_at.cmp(&b.uploaded_at),
+                FileSort::Filename => a.filename.to_lowercase().cmp(&b.filename.to_lowercase()),
+                FileSort::Size => a.size.cmp(&b.size),
+            };
+            // Tie-break on id so pages are stable
+            let ordering = ordering.then_with(|| a.id.cmp(&b.id));
+            match query.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+
+        let total = files.len();
+        let page = query.page();
+        let per_page = query.per_page();
+        let files = files
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect();
+
+        FileList {
+            files,
+            total,
+            page,
+            per_page,
+        }
+    }
+
+    /// Counts a download under the write lock, so concurrent downloads can never exceed the
+    /// allowance. A file that has expired or used up its allowance is refused with `NotFound`;
+    /// the download that uses up the allowance removes the record and then its blob.
+    pub fn record_download(&self, id: &str, now: chrono::DateTime<chrono::Utc>) -> io::Result<File> {
+        let mut files = self.files.write().map_err(|_| {
+            io::Error::other("Failed to acquire write lock")
+        })?;
+        let file = files
+            .get(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+        if file.is_expired(now) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "File has expired"));
+        }
+
+        let mut file = file.clone();
+        file.download_count += 1;
+        if !file.is_expired(now) {
+            self.journal.append(&JournalEntry::Download {
+                id: id.to_string(),
+                count: file.download_count,
+            })?;
+            files.insert(id.to_string(), file.clone());
+            return Ok(file);
+        }
+
+        self.journal.append(&JournalEntry::Remove { id: id.to_string() })?;
+        files.remove(id);
+        drop(files);
+        Self::remove_blob(&file);
+        Ok(file)
+    }
+
+    /// Removes a record and then its blob. A blob that cannot be deleted is left as an orphan
+    /// to be reported on the next start.
+    pub fn remove_file(&self, id: &str) -> io::Result<Option<File>> {
+        let mut files = self.files.write().map_err(|_| {
+            io::Error::other("Failed to acquire write lock")
+        })?;
+        if !files.contains_key(id) {
+            return Ok(None);
+        }
+
+        self.journal.append(&JournalEntry::Remove { id: id.to_string() })?;
+        let file = files.remove(id);
+        drop(files);
+
+        if let Some(file) = &file {
+            Self::remove_blob(file);
+        }
+
+        Ok(file)
+    }
+
+    fn remove_blob(file: &File) {
+        match fs::remove_file(&file.file_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("Failed to delete blob {}: {}", file.file_path.display(), e),
+        }
+    }
+
+    /// Blobs found at startup that have no metadata record.
+    pub fn orphaned_blobs(&self) -> &[PathBuf] {
+        &self.orphans
+    }
+
     pub fn generate_storage_path(&self) -> PathBuf {
         self.storage_path.clone()
     }
 
     pub fn generate_file_path(&self, id: &str) -> PathBuf {
-        self.generate_storage_path().join(id) 
+        self.generate_storage_path().join(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn write_blob(store: &FileStore, name: &str) -> File {
+        let path = store.generate_file_path(name);
+        fs::write(&path, b"blob").unwrap();
+        File::new(format!("{}.txt", name), 4, path)
+    }
+
+    #[test]
+    fn test_records_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        let file = write_blob(&store, "kept");
+        store.add_file(file.clone()).unwrap();
+        drop(store);
+
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        let loaded = store.get_file(&file.id).unwrap();
+        assert_eq!(loaded.filename, "kept.txt");
+        assert_eq!(loaded.file_path, file.file_path);
+        assert!(store.orphaned_blobs().is_empty());
+    }
+
+    #[test]
+    fn test_journal_is_compacted_once_enough_is_dead() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        let (kept, dropped) = (write_blob(&store, "kept"), write_blob(&store, "dropped"));
+        store.add_file(kept.clone()).unwrap();
+        store.add_file(dropped.clone()).unwrap();
+        store.remove_file(&dropped.id).unwrap();
+        for _ in 0..4 {
+            store.record_download(&kept.id, chrono::Utc::now()).unwrap();
+        }
+        let lines = || fs::read_to_string(dir.path().join("metadata.jsonl")).unwrap().lines().count();
+
+        // Seven entries for one record
+        assert_eq!(lines(), 7);
+        assert!(!store.compact_journal(7).unwrap());
+        assert!(store.compact_journal(6).unwrap());
+        assert_eq!(lines(), 1);
+        assert!(!store.compact_journal(1).unwrap());
+
+        store.record_download(&kept.id, chrono::Utc::now()).unwrap();
+        drop(store);
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(store.get_file(&kept.id).unwrap().download_count, 5);
+        assert!(store.get_file(&dropped.id).is_none());
+    }
+
+    #[test]
+    fn test_list_and_remove_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        for (name, size) in [("b", 1), ("a", 3), ("c", 2)] {
+            let mut file = write_blob(&store, name);
+            file.size = size;
+            store.add_file(file).unwrap();
+        }
+
+        let query = FileListQuery {
+            per_page: Some(2),
+            sort: FileSort::Size,
+            order: SortOrder::Asc,
+            ..Default::default()
+        };
+        let list = store.list_files(&query, chrono::Utc::now());
+        assert_eq!(list.total, 3);
+        let names: Vec<_> = list.files.iter().map(|file| file.filename.as_str()).collect();
+        assert_eq!(names, ["b.txt", "c.txt"]);
+
+        let query = FileListQuery {
+            filename: Some("A.TX".to_string()),
+            ..Default::default()
+        };
+        let found = store.list_files(&query, chrono::Utc::now()).files;
+        assert_eq!(found.len(), 1);
+
+        let removed = store.remove_file(&found[0].id).unwrap().unwrap();
+        assert!(!removed.file_path.exists());
+        assert!(store.remove_file(&found[0].id).unwrap().is_none());
+        drop(store);
+
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(store.list_files(&FileListQuery::default(), chrono::Utc::now()).total, 2);
+    }
+
+    #[test]
+    fn test_download_allowance_is_counted_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(FileStore::new(dir.path().to_path_buf()).unwrap());
+        let mut file = write_blob(&store, &uuid::Uuid::new_v4().to_string());
+        file.max_downloads = Some(3);
+        store.add_file(file.clone()).unwrap();
+
+        let now = chrono::Utc::now();
+        let granted: usize = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                let id = file.id.clone();
+                std::thread::spawn(move || store.record_download(&id, now).is_ok())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap() as usize)
+            .sum();
+        assert_eq!(granted, 3);
+        assert!(store.get_file(&file.id).is_none());
+        assert!(!file.file_path.exists());
+
+        let mut kept = write_blob(&store, &uuid::Uuid::new_v4().to_string());
+        kept.max_downloads = Some(5);
+        store.add_file(kept.clone()).unwrap();
+        store.record_download(&kept.id, now).unwrap();
+        drop(store);
+
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(store.get_file(&kept.id).unwrap().download_count, 1);
+    }
+
+    #[test]
+    fn test_reconcile_drops_missing_and_flags_orphans() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        let missing = write_blob(&store, "missing");
+        store.add_file(missing.clone()).unwrap();
+        fs::remove_file(&missing.file_path).unwrap();
+        let orphan = write_blob(&store, &uuid::Uuid::new_v4().to_string());
+        drop(store);
+
+        let store = FileStore::new(dir.path().to_path_buf()).unwrap();
+        assert!(store.get_file(&missing.id).is_none());
+        assert_eq!(store.orphaned_blobs(), &[orphan.file_path]);
     }
 }
@@ -0,0 +1,119 @@
+use std::fs::{self, File as FsFile, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use crate::models::file::File;
+
+const JOURNAL_FILE_NAME: &str = "metadata.jsonl";
+
+/// One line of the metadata journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Put {
+        file: File,
+        /// Blob file name, relative to the storage directory
+        blob: String,
+    },
+    Remove {
+        id: String,
+    },
+    /// A file's download count changed; cheaper than rewriting the whole record
+    Download {
+        id: String,
+        count: u32,
+    },
+}
+
+/// Append-only JSON-lines log of file metadata changes, kept next to the blobs.
+pub struct Journal {
+    path: PathBuf,
+    writer: Mutex<FsFile>,
+    /// Entries written since the last compaction, counting the snapshot it wrote
+    entries: AtomicUsize,
+}
+
+impl Journal {
+    pub fn open(storage_path: &Path) -> io::Result<Self> {
+        let path = storage_path.join(JOURNAL_FILE_NAME);
+        let writer = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self {
+            path,
+            writer: Mutex::new(writer),
+            entries: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Entries in the journal, as far as this process wrote them.
+    pub fn entry_count(&self) -> usize {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    /// Reads every entry in the journal. A torn trailing line from a crash mid-write is skipped.
+    pub fn replay(&self) -> io::Result<Vec<JournalEntry>> {
+        let reader = BufReader::new(FsFile::open(&self.path)?);
+        let mut entries = Vec::new();
+
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!(
+                    "Skipping unreadable journal entry at {}:{}: {}",
+                    self.path.display(),
+                    line_no + 1,
+                    e
+                ),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("Failed to acquire journal lock"))?;
+        writer.write_all(&line)?;
+        writer.sync_data()?;
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replaces the journal with a snapshot of `entries`, written atomically via a rename.
+    pub fn compact<'a>(&self, entries: impl IntoIterator<Item = &'a JournalEntry>) -> io::Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("Failed to acquire journal lock"))?;
+
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        let mut tmp = FsFile::create(&tmp_path)?;
+        let mut written = 0;
+        for entry in entries {
+            serde_json::to_writer(&mut tmp, entry)?;
+            tmp.write_all(b"\n")?;
+            written += 1;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        *writer = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries.store(written, Ordering::Relaxed);
+        Ok(())
+    }
+}
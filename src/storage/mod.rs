@@ -1 +1,3 @@
-pub mod file_store; 
\ No newline at end of file
+pub mod file_store; 
+pub mod journal;
+pub mod trust_store;
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A device that completed pairing. Only a hash of its token is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedDevice {
+    pub device_id: String,
+    pub name: String,
+    pub token_sha256: String,
+    pub paired_at: DateTime<Utc>,
+}
+
+/// Paired devices, optionally persisted as a JSON file so tokens outlive a restart.
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    devices: HashMap<String, TrustedDevice>,
+}
+
+impl TrustStore {
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let devices = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice::<Vec<TrustedDevice>>(&contents)?
+                .into_iter()
+                .map(|device| (device.device_id.clone(), device))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        log::info!("Loaded {} trusted devices from {}", devices.len(), path.display());
+        Ok(Self {
+            path: Some(path),
+            devices,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn contains(&self, device_id: &str) -> bool {
+        self.devices.contains_key(device_id)
+    }
+
+    /// Returns the id of the device `token` was issued to.
+    pub fn authenticate(&self, token: &str) -> Option<String> {
+        let token_sha256 = hash_token(token);
+        self.devices
+            .values()
+            .find(|device| device.token_sha256 == token_sha256)
+            .map(|device| device.device_id.clone())
+    }
+
+    /// Trusts `device_id` and returns the freshly issued token, replacing any earlier one.
+    /// Nothing changes unless the new list was saved first.
+    pub fn trust(&mut self, device_id: &str, name: &str) -> io::Result<String> {
+        let token = generate_token();
+        let mut devices = self.devices.clone();
+        devices.insert(
+            device_id.to_string(),
+            TrustedDevice {
+                device_id: device_id.to_string(),
+                name: name.to_string(),
+                token_sha256: hash_token(&token),
+                paired_at: Utc::now(),
+            },
+        );
+        self.persist(&devices)?;
+        self.devices = devices;
+        Ok(token)
+    }
+
+    fn persist(&self, devices: &HashMap<String, TrustedDevice>) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let devices: Vec<&TrustedDevice> = devices.values().collect();
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&devices)?)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// A random secret made of two v4 UUIDs, hex encoded.
+pub fn generate_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_save_leaves_devices_untrusted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("state").join("trusted_devices.json");
+        let mut store = TrustStore::open(path.clone()).unwrap();
+        // The directory does not exist, so saving fails
+        assert!(store.trust("phone", "Phone").is_err());
+        assert!(!store.contains("phone"));
+        assert!(store.is_empty());
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let token = store.trust("phone", "Phone").unwrap();
+        assert_eq!(store.authenticate(&token).as_deref(), Some("phone"));
+        let reopened = TrustStore::open(path).unwrap();
+        assert_eq!(reopened.authenticate(&token).as_deref(), Some("phone"));
+    }
+}
@@ -0,0 +1,4 @@
+mod file_transfer_test;
+mod rate_limit_test;
+mod server_test;
+mod websocket_test;
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+use actix_web::{web, App, HttpServer};
+use crate::config::WebSocketConfig;
+use crate::controllers::file_controller::{get_file, upload_file};
+use crate::controllers::websocket_controller::websocket_route;
+use crate::services::discovery_service::DiscoveryService;
+use crate::services::file_service::FileService;
+use crate::storage::trust_store::TrustStore;
+use crate::websocket::registry::SessionRegistry;
+
+#[actix_rt::test]
+async fn test_server_startup() {
+    let storage_path = tempfile::tempdir().unwrap().path().to_path_buf();
+    let file_service = web::Data::new(FileService::new(storage_path).unwrap());
+    let discovery_service = Arc::new(DiscoveryService::new(TrustStore::in_memory()));
+    let sessions = Arc::new(SessionRegistry::new());
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(file_service.clone())
+            .app_data(web::Data::new(Arc::clone(&discovery_service)))
+            .app_data(web::Data::new(Arc::clone(&sessions)))
+            .app_data(web::Data::new(WebSocketConfig::default()))
+            .service(
+                web::scope("/api")
+                    .route("/upload", web::post().to(upload_file))
+                    .route("/files/{id}", web::get().to(get_file))
+                    .route("/ws", web::get().to(websocket_route)),
+            )
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+
+    drop(server.run()); // Server started successfully
+}
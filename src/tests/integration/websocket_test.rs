@@ -1,24 +1,49 @@
 use actix_web::{test, web, App};
 use actix_web::http::StatusCode;
+use actix_web::http::header::AUTHORIZATION;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::config::WebSocketConfig;
 use crate::services::discovery_service::DiscoveryService;
+use crate::storage::trust_store::TrustStore;
 use crate::controllers::websocket_controller::websocket_route;
+use crate::websocket::registry::SessionRegistry;
+use crate::websocket::transfers::TransferManager;
 
 #[actix_rt::test]
 async fn test_websocket_connection() {
-    let discovery_service = Arc::new(DiscoveryService::new());
-    
+    let discovery_service = Arc::new(DiscoveryService::new(TrustStore::in_memory()));
+    let sessions = Arc::new(SessionRegistry::new());
+    let transfers = Arc::new(TransferManager::new(Arc::clone(&sessions), Duration::from_secs(30)));
+    let device_id = uuid::Uuid::new_v4().to_string();
+    let token = discovery_service
+        .bootstrap(&device_id, &discovery_service.bootstrap_pin().unwrap())
+        .unwrap();
+
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(Arc::clone(&discovery_service)))
+            .app_data(web::Data::new(Arc::clone(&sessions)))
+            .app_data(web::Data::new(transfers))
+            .app_data(web::Data::new(WebSocketConfig::default()))
             .route("/ws", web::get().to(websocket_route))
     ).await;
-    
-    let req = test::TestRequest::get()
-        .uri("/ws?name=test-device")
-        .to_request();
-        
-    let resp = test::call_service(&app, req).await;
+
+    let upgrade = |token: &str| {
+        test::TestRequest::get()
+            .uri("/ws?name=test-device")
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Connection", "Upgrade"))
+            .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .insert_header(("Sec-WebSocket-Version", "13"))
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, upgrade(&token)).await;
     assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
-}
\ No newline at end of file
+
+    let resp = test::call_service(&app, upgrade("not-a-token")).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
@@ -1,5 +1,5 @@
 use chrono::Utc;
-use crate::models::device::DeviceInfo;
+use crate::models::device::{DeviceInfo, TrustState};
 
 #[test]
 fn test_device_info_creation() {
@@ -7,8 +7,11 @@ fn test_device_info_creation() {
         id: "test-id".to_string(),
         name: "test-device".to_string(),
         last_seen: Utc::now(),
+        trust: TrustState::Pending,
+        online: true,
+        features: Vec::new(),
     };
-    
+
     assert_eq!(device.id, "test-id");
     assert_eq!(device.name, "test-device");
 }
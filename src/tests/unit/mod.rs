@@ -0,0 +1,2 @@
+mod device_info_test;
+mod discovery_service_test;
@@ -0,0 +1,129 @@
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use rustls::pki_types::CertificateDer;
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
+use crate::config::TlsConfig;
+use crate::models::tls::TlsInfo;
+
+/// A loaded certificate, ready to hand to the HTTP server.
+pub struct TlsIdentity {
+    pub server_config: ServerConfig,
+    pub info: TlsInfo,
+}
+
+/// Loads the configured certificate, generating a self-signed one first if asked to and none
+/// exists yet. Returns `None` when TLS is not configured.
+pub fn load_identity(config: &TlsConfig) -> io::Result<Option<TlsIdentity>> {
+    let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) else {
+        return Ok(None);
+    };
+
+    if config.self_signed && !cert_path.exists() && !key_path.exists() {
+        generate_self_signed(cert_path, key_path, &config.self_signed_names)?;
+    }
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(open(cert_path)?))
+        .collect::<io::Result<Vec<CertificateDer<'static>>>>()?;
+    let Some(leaf) = certs.first() else {
+        return Err(invalid(format!("No certificate found in {}", cert_path.display())));
+    };
+    let fingerprint = fingerprint(leaf);
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(open(key_path)?))?
+        .ok_or_else(|| invalid(format!("No private key found in {}", key_path.display())))?;
+
+    let server_config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(format!("Certificate and key do not form a usable identity: {}", e)))?;
+
+    Ok(Some(TlsIdentity {
+        server_config,
+        info: TlsInfo {
+            enabled: true,
+            self_signed: config.self_signed,
+            fingerprint_sha256: Some(fingerprint),
+        },
+    }))
+}
+
+/// SHA-256 of the DER certificate as colon-separated upper-case hex, the form browsers show.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn generate_self_signed(cert_path: &Path, key_path: &Path, names: &[String]) -> io::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(names.to_vec())
+        .map_err(|e| io::Error::other(format!("Failed to generate a self-signed certificate: {}", e)))?;
+
+    for path in [cert_path, key_path] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    fs::write(cert_path, generated.cert.pem())?;
+
+    // The key must not be readable by other local users
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(key_path)?
+        .write_all(generated.key_pair.serialize_pem().as_bytes())?;
+
+    log::info!(
+        "Generated a self-signed certificate for {} at {}",
+        names.join(", "),
+        cert_path.display()
+    );
+    Ok(())
+}
+
+fn open(path: &Path) -> io::Result<fs::File> {
+    fs::File::open(path).map_err(|e| io::Error::new(e.kind(), format!("Failed to open {}: {}", path.display(), e)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_signed_certificate_is_generated_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TlsConfig {
+            cert_path: Some(dir.path().join("tls/cert.pem")),
+            key_path: Some(dir.path().join("tls/key.pem")),
+            self_signed: true,
+            ..TlsConfig::default()
+        };
+
+        let first = load_identity(&config).unwrap().unwrap();
+        let fingerprint = first.info.fingerprint_sha256.unwrap();
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+
+        // The persisted certificate is reused, so pinned fingerprints stay valid
+        let second = load_identity(&config).unwrap().unwrap();
+        assert_eq!(second.info.fingerprint_sha256.unwrap(), fingerprint);
+
+        let missing = TlsConfig {
+            cert_path: Some(dir.path().join("missing.pem")),
+            key_path: Some(dir.path().join("missing.key")),
+            ..TlsConfig::default()
+        };
+        assert!(load_identity(&missing).is_err());
+    }
+}
@@ -1,27 +1,72 @@
-use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
 use actix_web_actors::ws;
+use bytes::Bytes;
 use chrono::Utc;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use uuid::Uuid;
 
-use crate::services::discovery_service::DiscoveryService;
+use crate::config::WebSocketConfig;
+use crate::middleware::logger::RequestId;
+use crate::models::device::Feature;
+use crate::services::discovery_service::{DiscoveryService, PairingStart};
+use super::frame::{Assembled, ChunkFrame, FrameAssembler};
+use super::handshake;
 use super::message::FileTransferMessage;
+use super::registry::SessionRegistry;
+use super::transfers::{TransferEvent, TransferManager};
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+/// A message relayed from another session, to be written to this session's socket.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Deliver(pub FileTransferMessage);
+
+/// A binary chunk frame relayed from another session, written to this session's socket as is.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeliverChunk(pub Bytes);
+
+/// Tells a session that a newer connection for the same device took its place.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Replaced;
+
+/// Tells a session the server is shutting down and its socket should be closed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServerClosing;
 
 pub struct FileTransferWs {
     id: String,
     device_name: String,
     hb: Instant,
     discovery_service: Arc<DiscoveryService>,
+    sessions: Arc<SessionRegistry>,
+    transfers: Arc<TransferManager>,
+    timing: WebSocketConfig,
+    /// Id of the upgrade request, so the session's log lines can be matched to its access log line
+    request_id: RequestId,
+    /// Reassembles messages the client sends in fragments
+    assembler: FrameAssembler,
+    /// Protocol version agreed on through `Hello`; `None` until the client sends one
+    protocol_version: Option<u32>,
+    /// Set when the client closed the socket itself rather than dropping off the network
+    closed_by_client: bool,
 }
 
 impl FileTransferWs {
-    pub fn new(device_name: String, discovery_service: Arc<DiscoveryService>) -> Self {
-        let id = Uuid::new_v4().to_string();
+    /// `device_id` is the persistent id the client presented, either directly or through its
+    /// pairing token. Without one, the connection gets a fresh id.
+    pub fn new(
+        device_name: String,
+        device_id: Option<String>,
+        discovery_service: Arc<DiscoveryService>,
+        sessions: Arc<SessionRegistry>,
+        transfers: Arc<TransferManager>,
+        timing: WebSocketConfig,
+        request_id: RequestId,
+    ) -> Self {
+        let id = device_id.unwrap_or_else(|| Uuid::new_v4().to_string());
         discovery_service.register_device(id.clone(), device_name.clone());
         
         Self {
@@ -29,49 +74,525 @@ impl FileTransferWs {
             device_name,
             hb: Instant::now(),
             discovery_service,
+            sessions,
+            transfers,
+            assembler: FrameAssembler::new(timing.max_frame_size),
+            protocol_version: None,
+            timing,
+            request_id,
+            closed_by_client: false,
+        }
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.id
+    }
+
+    fn send_message(ctx: &mut <Self as Actor>::Context, message: &FileTransferMessage) {
+        if let Ok(json) = serde_json::to_string(message) {
+            ctx.text(json);
+        }
+    }
+
+    /// Sends the full device list; changes to it are pushed by `DiscoveryService` after that.
+    fn send_device_list(&self, ctx: &mut <Self as Actor>::Context) {
+        let response = FileTransferMessage::DeviceList {
+            devices: self.discovery_service.get_nearby_devices(),
+            timestamp: Utc::now(),
+        };
+        Self::send_message(ctx, &response);
+    }
+
+    /// Checks a transfer message against the state of its transfer, then forwards it to the
+    /// other device taking part.
+    fn relay(&self, mut message: FileTransferMessage, ctx: &mut <Self as Actor>::Context) {
+        let receiver_id = match self.route(&mut message) {
+            Ok(receiver_id) => receiver_id,
+            Err(error) => {
+                Self::send_message(ctx, &error);
+                return;
+            }
+        };
+
+        let chunk = match &message {
+            FileTransferMessage::FileChunk { transfer_id, data, .. } => Some((transfer_id.clone(), decoded_len(data))),
+            _ => None,
+        };
+
+        if let Some(addr) = self.sessions.get(&receiver_id) {
+            addr.do_send(Deliver(message));
+        } else {
+            // The receiver may be reconnecting; hold the message until it is back
+            let request_id = match &message {
+                FileTransferMessage::TransferRequest { file_id, .. } => Some(file_id.clone()),
+                _ => None,
+            };
+            match self.discovery_service.queue_message(&receiver_id, message) {
+                Ok(()) => log::info!("Receiver {} is offline, message queued request_id={}", receiver_id, self.request_id),
+                Err(e) => {
+                    log::warn!("{} request_id={}", e, self.request_id);
+                    // A request that never arrives cannot be answered
+                    if let Some(file_id) = request_id {
+                        let _ = self.transfers.apply(&file_id, &self.id, None, TransferEvent::Cancel);
+                    }
+                    Self::send_message(ctx, &FileTransferMessage::error(e));
+                    return;
+                }
+            }
+        }
+
+        if let Some((transfer_id, bytes)) = chunk {
+            self.transfers.record_bytes(&transfer_id, bytes);
+        }
+    }
+
+    /// Advances the transfer `message` belongs to and returns the device to forward it to, or
+    /// the `Error` to answer with when the transfer does not allow it.
+    fn route(&self, message: &mut FileTransferMessage) -> Result<String, FileTransferMessage> {
+        use TransferEvent::*;
+
+        let (transfer_id, receiver_id, event) = match message {
+            FileTransferMessage::TransferRequest {
+                file_id,
+                filename,
+                size,
+                sender_id,
+                receiver_id,
+                ..
+            } => {
+                if !self.discovery_service.is_trusted(receiver_id) {
+                    let error = format!("Receiver {} is unknown", receiver_id);
+                    return Err(FileTransferMessage::transfer_error(file_id, error));
+                }
+                // The sender is always the device behind this connection
+                sender_id.clone_from(&self.id);
+                self.transfers
+                    .request(file_id, &self.id, receiver_id, filename, *size)
+                    .map_err(|e| FileTransferMessage::transfer_error(file_id, e))?;
+                return Ok(receiver_id.clone());
+            }
+            FileTransferMessage::TransferAccept { file_id, .. } => (file_id.as_str(), None, Accept),
+            FileTransferMessage::TransferReject { file_id, .. } => (file_id.as_str(), None, Reject),
+            FileTransferMessage::TransferCancel { file_id, .. } => (file_id.as_str(), None, Cancel),
+            FileTransferMessage::FileTransferInit {
+                transfer_id,
+                file_size,
+                sender_id,
+                receiver_id,
+                ..
+            } => {
+                sender_id.clone_from(&self.id);
+                (transfer_id.as_str(), Some(receiver_id.as_str()), Start(*file_size))
+            }
+            FileTransferMessage::FileChunk {
+                transfer_id,
+                chunk_index,
+                receiver_id,
+                ..
+            } => (transfer_id.as_str(), Some(receiver_id.as_str()), Chunk(*chunk_index as u64)),
+            FileTransferMessage::FileTransferComplete { transfer_id, receiver_id } => {
+                (transfer_id.as_str(), Some(receiver_id.as_str()), Complete)
+            }
+            // Answered by the server itself before anything is relayed
+            FileTransferMessage::Hello { .. }
+            | FileTransferMessage::DeviceDiscovery { .. }
+            | FileTransferMessage::BootstrapPairing { .. }
+            | FileTransferMessage::PairingApprove { .. }
+            | FileTransferMessage::PairingReject { .. }
+            | FileTransferMessage::ChunkAck { .. } => {
+                return Err(FileTransferMessage::error("Message has no receiver"));
+            }
+            FileTransferMessage::Welcome { .. }
+            | FileTransferMessage::DeviceList { .. }
+            | FileTransferMessage::DeviceJoined { .. }
+            | FileTransferMessage::DeviceUpdated { .. }
+            | FileTransferMessage::DeviceLeft { .. }
+            | FileTransferMessage::TransferProgress { .. }
+            | FileTransferMessage::BootstrapRequired { .. }
+            | FileTransferMessage::PairingRequired { .. }
+            | FileTransferMessage::PairingRequest { .. }
+            | FileTransferMessage::PairingApproved { .. }
+            | FileTransferMessage::ServerShutdown { .. }
+            | FileTransferMessage::Error { .. } => {
+                return Err(FileTransferMessage::error("Only the server sends this message"));
+            }
+        };
+
+        self.transfers
+            .apply(transfer_id, &self.id, receiver_id, event)
+            .map_err(|e| FileTransferMessage::transfer_error(transfer_id, e))
+    }
+
+    /// Fails the transfers this device takes part in and tells the devices on the other side.
+    fn fail_transfers(&self) {
+        for transfer in self.transfers.fail_device(&self.id) {
+            if let Some(addr) = self.sessions.get(transfer.peer_of(&self.id)) {
+                addr.do_send(Deliver(FileTransferMessage::transfer_error(
+                    &transfer.id,
+                    format!("Transfer {} failed: {} disconnected", transfer.id, self.device_name),
+                )));
+            }
+        }
+    }
+
+    /// Forwards a binary chunk frame to its receiver. Only the header is read; chunks are not
+    /// queued for offline receivers.
+    fn relay_chunk(&self, frame: Bytes, ctx: &mut <Self as Actor>::Context) {
+        if !self.discovery_service.is_trusted(&self.id) {
+            Self::send_message(ctx, &FileTransferMessage::error("Device is not paired"));
+            return;
+        }
+        if !self.discovery_service.supports(&self.id, Feature::BinaryChunks) {
+            Self::send_message(ctx, &FileTransferMessage::error("Binary chunks were not agreed on in Hello"));
+            return;
+        }
+        let chunk = match ChunkFrame::decode(&frame) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                Self::send_message(ctx, &FileTransferMessage::error(format!("Invalid chunk frame: {}", e)));
+                return;
+            }
+        };
+        if !self.discovery_service.supports(&chunk.receiver_id, Feature::BinaryChunks) {
+            let error = format!("Receiver {} does not accept binary chunks", chunk.receiver_id);
+            Self::send_message(ctx, &FileTransferMessage::transfer_error(&chunk.transfer_id, error));
+            return;
+        }
+
+        let event = TransferEvent::Chunk(chunk.chunk_index.into());
+        if let Err(e) = self.transfers.apply(&chunk.transfer_id, &self.id, Some(&chunk.receiver_id), event) {
+            Self::send_message(ctx, &FileTransferMessage::transfer_error(&chunk.transfer_id, e));
+            return;
+        }
+
+        match self.sessions.get(&chunk.receiver_id) {
+            Some(addr) => {
+                log::debug!(
+                    "Relaying chunk {} of transfer {} ({} bytes) to {} request_id={}",
+                    chunk.chunk_index,
+                    chunk.transfer_id,
+                    chunk.data.len(),
+                    chunk.receiver_id,
+                    self.request_id
+                );
+                addr.do_send(DeliverChunk(frame));
+                self.transfers.record_bytes(&chunk.transfer_id, chunk.data.len() as u64);
+            }
+            None => Self::send_message(
+                ctx,
+                &FileTransferMessage::error(format!("Receiver {} is not connected", chunk.receiver_id)),
+            ),
+        }
+    }
+
+    /// Passes the receiver's acknowledgement on to the sender, which may then relay more chunks.
+    /// Acknowledgements are not queued for a sender that is offline, as its transfer has failed.
+    fn relay_ack(&self, transfer_id: String, chunk_index: u64, ctx: &mut <Self as Actor>::Context) {
+        match self.transfers.acknowledge(&transfer_id, &self.id, chunk_index) {
+            Ok(Some(sender_id)) => {
+                if let Some(addr) = self.sessions.get(&sender_id) {
+                    addr.do_send(Deliver(FileTransferMessage::ChunkAck { transfer_id, chunk_index }));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => Self::send_message(ctx, &FileTransferMessage::transfer_error(&transfer_id, e)),
+        }
+    }
+
+    fn handle_text(&mut self, text: &str, ctx: &mut <Self as Actor>::Context) {
+        let message = match serde_json::from_str::<FileTransferMessage>(text) {
+            Ok(message) => message,
+            Err(e) => {
+                Self::send_message(ctx, &FileTransferMessage::error(format!("Invalid message: {}", e)));
+                return;
+            }
+        };
+        // Unpaired devices say hello too, so the handshake comes before the pairing check
+        if let FileTransferMessage::Hello { .. } = message {
+            self.answer_hello(message, ctx);
+            return;
+        }
+        if let FileTransferMessage::BootstrapPairing { pin } = message {
+            self.bootstrap(&pin, ctx);
+            return;
+        }
+        if !self.discovery_service.is_trusted(&self.id) {
+            Self::send_message(ctx, &FileTransferMessage::error("Device is not paired"));
+            return;
+        }
+
+        match message {
+            FileTransferMessage::DeviceDiscovery { .. } => self.send_device_list(ctx),
+            message @ (FileTransferMessage::PairingApprove { .. }
+            | FileTransferMessage::PairingReject { .. }) => {
+                self.answer_pairing(message, ctx);
+            }
+            FileTransferMessage::ChunkAck { transfer_id, chunk_index } => {
+                self.relay_ack(transfer_id, chunk_index, ctx);
+            }
+            message => self.relay(message, ctx),
+        }
+    }
+
+    /// Asks the trusted devices to approve this one, or for the setup PIN if none exist yet.
+    fn start_pairing(&self, ctx: &mut <Self as Actor>::Context) {
+        match self.discovery_service.begin_pairing(&self.id) {
+            Ok(PairingStart::Bootstrap { resume_token }) => {
+                log::info!("Device {} awaiting the setup PIN request_id={}", self.id, self.request_id);
+                Self::send_message(
+                    ctx,
+                    &FileTransferMessage::BootstrapRequired {
+                        device_id: self.id.clone(),
+                        resume_token,
+                    },
+                );
+            }
+            Ok(PairingStart::Pending {
+                resume_token,
+                pairing_id,
+                pin,
+                expires_at,
+            }) => {
+                log::info!("Device {} awaiting pairing approval request_id={}", self.id, self.request_id);
+                let request = FileTransferMessage::PairingRequest {
+                    pairing_id: pairing_id.clone(),
+                    device_id: self.id.clone(),
+                    device_name: self.device_name.clone(),
+                    timestamp: Utc::now(),
+                };
+                for device_id in self.discovery_service.trusted_device_ids() {
+                    if let Some(addr) = self.sessions.get(&device_id) {
+                        addr.do_send(Deliver(request.clone()));
+                    }
+                }
+
+                Self::send_message(
+                    ctx,
+                    &FileTransferMessage::PairingRequired {
+                        device_id: self.id.clone(),
+                        resume_token,
+                        pairing_id,
+                        pin,
+                        expires_at,
+                    },
+                );
+            }
+            Err(e) => {
+                log::error!("Failed to start pairing for {}: {} request_id={}", self.id, e, self.request_id);
+                Self::send_message(ctx, &FileTransferMessage::error("Pairing is unavailable"));
+            }
+        }
+    }
+
+    /// Pairs this device as the first one when `pin` is the setup PIN from the server log.
+    fn bootstrap(&self, pin: &str, ctx: &mut <Self as Actor>::Context) {
+        if self.discovery_service.is_trusted(&self.id) {
+            Self::send_message(ctx, &FileTransferMessage::error("Device is already paired"));
+            return;
+        }
+        match self.discovery_service.bootstrap(&self.id, pin) {
+            Ok(token) => {
+                Self::send_message(
+                    ctx,
+                    &FileTransferMessage::PairingApproved {
+                        device_id: self.id.clone(),
+                        token,
+                    },
+                );
+                self.send_device_list(ctx);
+            }
+            Err(e) => Self::send_message(ctx, &FileTransferMessage::error(e)),
+        }
+    }
+
+    /// Agrees on a protocol version and features with the client, or disconnects it when there
+    /// is no version both sides speak.
+    fn answer_hello(&mut self, message: FileTransferMessage, ctx: &mut <Self as Actor>::Context) {
+        let FileTransferMessage::Hello {
+            protocol_version,
+            min_protocol_version,
+            client_version,
+            features,
+        } = message
+        else {
+            return;
+        };
+        if self.protocol_version.is_some() {
+            Self::send_message(ctx, &FileTransferMessage::error("Hello was already received"));
+            return;
+        }
+
+        match handshake::negotiate(protocol_version, min_protocol_version, &features) {
+            Ok((version, features)) => {
+                log::info!(
+                    "Device {} runs {:?}, speaking protocol {} with {:?} request_id={}",
+                    self.id,
+                    client_version,
+                    version,
+                    features,
+                    self.request_id
+                );
+                self.protocol_version = Some(version);
+                self.discovery_service.set_features(&self.id, features.clone());
+                Self::send_message(
+                    ctx,
+                    &FileTransferMessage::Welcome {
+                        protocol_version: version,
+                        server_version: env!("CARGO_PKG_VERSION").to_string(),
+                        features,
+                        timestamp: Utc::now(),
+                    },
+                );
+            }
+            Err(e) => {
+                log::warn!("Refusing {} ({:?}): {} request_id={}", self.id, client_version, e, self.request_id);
+                Self::send_message(ctx, &FileTransferMessage::error(e.clone()));
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Protocol,
+                    description: Some(e),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+
+    /// Handles a trusted device's answer to a `PairingRequest`.
+    fn answer_pairing(&self, message: FileTransferMessage, ctx: &mut <Self as Actor>::Context) {
+        match message {
+            FileTransferMessage::PairingApprove { pairing_id, pin } => {
+                match self.discovery_service.approve_pairing(&pairing_id, &pin) {
+                    Ok((device_id, token)) => {
+                        if let Some(addr) = self.sessions.get(&device_id) {
+                            addr.do_send(Deliver(FileTransferMessage::PairingApproved {
+                                device_id,
+                                token,
+                            }));
+                        }
+                    }
+                    Err(e) => Self::send_message(ctx, &FileTransferMessage::error(e)),
+                }
+            }
+            FileTransferMessage::PairingReject { pairing_id } => {
+                if let Some(device_id) = self.discovery_service.reject_pairing(&pairing_id) {
+                    if let Some(addr) = self.sessions.get(&device_id) {
+                        addr.do_send(Deliver(FileTransferMessage::error("Pairing was rejected")));
+                    }
+                }
+            }
+            _ => {}
         }
     }
 
     fn heartbeat(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                log::info!("Client timeout, disconnecting: {}", act.id);
-                act.discovery_service.remove_device(&act.id);
+        ctx.run_interval(self.timing.heartbeat_interval(), |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.timing.client_timeout() {
+                log::info!("Client timeout, disconnecting: {} request_id={}", act.id, act.request_id);
                 ctx.stop();
                 return;
             }
+            act.discovery_service.update_device_timestamp(&act.id);
             ctx.ping(b"");
         });
     }
+}
 
-    fn start_discovery(&self, ctx: &mut <Self as Actor>::Context) {
-        ctx.run_interval(DISCOVERY_INTERVAL, |act, ctx| {
-            act.discovery_service.update_device_timestamp(&act.id);
-            let devices = act.discovery_service.get_nearby_devices();
-            let message = FileTransferMessage::DeviceList {
-                devices,
-                timestamp: Utc::now(),
-            };
-            
-            if let Ok(json) = serde_json::to_string(&message) {
-                ctx.text(json);
-            }
-        });
-    }
+/// Size of what a base64 string decodes to, worked out without decoding it.
+fn decoded_len(data: &str) -> u64 {
+    let padding = data.bytes().rev().take_while(|&b| b == b'=').count();
+    (data.len() / 4 * 3).saturating_sub(padding) as u64
 }
 
 impl Actor for FileTransferWs {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        log::info!("WebSocket connection started for device: {}", self.device_name);
+        log::info!(
+            "WebSocket connection started for device: {} ({}) request_id={}",
+            self.device_name,
+            self.id,
+            self.request_id
+        );
+        if let Some(previous) = self.sessions.register(self.id.clone(), ctx.address()) {
+            log::info!("Device {} reconnected, closing its previous session request_id={}", self.id, self.request_id);
+            previous.do_send(Replaced);
+            // Transfers cannot carry on over a new socket
+            self.fail_transfers();
+        }
+
+        if self.discovery_service.is_trusted(&self.id) {
+            self.send_device_list(ctx);
+            for message in self.discovery_service.take_queued(&self.id) {
+                Self::send_message(ctx, &message);
+            }
+        } else {
+            self.start_pairing(ctx);
+        }
         self.heartbeat(ctx);
-        self.start_discovery(ctx);
     }
 
-    fn stopped(&mut self, _: &mut Self::Context) {
-        log::info!("WebSocket connection stopped for device: {}", self.device_name);
-        self.discovery_service.remove_device(&self.id);
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        log::info!(
+            "WebSocket connection stopped for device: {} ({}) request_id={}",
+            self.device_name,
+            self.id,
+            self.request_id
+        );
+
+        // A replaced session leaves the device to the connection that took over
+        if !self.sessions.unregister(&self.id, &ctx.address()) {
+            return;
+        }
+        self.fail_transfers();
+
+        if self.closed_by_client {
+            self.discovery_service.remove_device(&self.id);
+        } else {
+            self.discovery_service.mark_offline(&self.id);
+        }
+    }
+}
+
+impl Handler<Replaced> for FileTransferWs {
+    type Result = ();
+
+    fn handle(&mut self, _: Replaced, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("Replaced by a newer connection for this device".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ServerClosing> for FileTransferWs {
+    type Result = ();
+
+    fn handle(&mut self, _: ServerClosing, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("Server is shutting down".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<Deliver> for FileTransferWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: Deliver, ctx: &mut Self::Context) {
+        Self::send_message(ctx, &msg.0);
+        // A device that was just paired has not seen the other devices yet
+        if matches!(msg.0, FileTransferMessage::PairingApproved { .. }) {
+            self.send_device_list(ctx);
+        }
+    }
+}
+
+impl Handler<DeliverChunk> for FileTransferWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliverChunk, ctx: &mut Self::Context) {
+        ctx.binary(msg.0);
     }
 }
 
@@ -85,31 +606,285 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for FileTransferWs {
             Ok(ws::Message::Pong(_)) => {
                 self.hb = Instant::now();
             }
-            Ok(ws::Message::Text(text)) => {
-                if let Ok(message) = serde_json::from_str::<FileTransferMessage>(&text) {
-                    match message {
-                        FileTransferMessage::DeviceDiscovery { .. } => {
-                            let devices = self.discovery_service.get_nearby_devices();
-                            let response = FileTransferMessage::DeviceList {
-                                devices,
-                                timestamp: Utc::now(),
-                            };
-                            if let Ok(json) = serde_json::to_string(&response) {
-                                ctx.text(json);
-                            }
-                        }
-                        _ => {
-                            ctx.text(text);
-                        }
-                    }
+            Ok(ws::Message::Text(text)) => self.handle_text(&text, ctx),
+            Ok(ws::Message::Binary(frame)) => self.relay_chunk(frame, ctx),
+            Ok(ws::Message::Continuation(item)) => match self.assembler.push(item) {
+                Ok(Some(Assembled::Text(text))) => self.handle_text(&text, ctx),
+                Ok(Some(Assembled::Binary(frame))) => self.relay_chunk(frame, ctx),
+                Ok(None) => {}
+                Err(reason) => {
+                    log::warn!(
+                        "Closing WebSocket of {}: {} request_id={}",
+                        self.id,
+                        reason.description.as_deref().unwrap_or_default(),
+                        self.request_id
+                    );
+                    ctx.close(Some(reason));
+                    ctx.stop();
                 }
-            }
+            },
             Ok(ws::Message::Close(reason)) => {
-                self.discovery_service.remove_device(&self.id);
+                self.closed_by_client = true;
                 ctx.close(reason);
                 ctx.stop();
             }
-            _ => ctx.stop(),
+            Ok(ws::Message::Nop) => {}
+            Err(ws::ProtocolError::Overflow) => {
+                log::warn!("Closing WebSocket of {}: frame is too large request_id={}", self.id, self.request_id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Size,
+                    description: Some(format!("Frames are limited to {} bytes", self.timing.max_frame_size)),
+                }));
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use actix_web::{web, App, HttpServer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::controllers::websocket_controller::websocket_route;
+    use crate::storage::trust_store::TrustStore;
+
+    /// Just enough of a WebSocket client to drive a session over a real socket.
+    struct Client(TcpStream);
+
+    impl Client {
+        async fn connect(addr: std::net::SocketAddr, token: &str) -> Self {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "GET /api/ws?name=test HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+                 Authorization: Bearer {}\r\n\r\n",
+                addr, token
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+            Self(stream)
+        }
+
+        async fn send(&mut self, message: &FileTransferMessage) {
+            let payload = serde_json::to_vec(message).unwrap();
+            // Client frames must be masked; an all-zero mask leaves the payload as it is
+            let mut frame = vec![0x81];
+            if payload.len() < 126 {
+                frame.push(0x80 | payload.len() as u8);
+            } else {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            }
+            frame.extend_from_slice(&[0; 4]);
+            frame.extend_from_slice(&payload);
+            self.0.write_all(&frame).await.unwrap();
+        }
+
+        /// The next message other than presence and progress updates.
+        async fn recv(&mut self) -> FileTransferMessage {
+            loop {
+                match self.recv_any().await {
+                    FileTransferMessage::DeviceList { .. }
+                    | FileTransferMessage::DeviceJoined { .. }
+                    | FileTransferMessage::DeviceUpdated { .. }
+                    | FileTransferMessage::DeviceLeft { .. }
+                    | FileTransferMessage::TransferProgress { .. } => continue,
+                    message => return message,
+                }
+            }
+        }
+
+        /// The next text message, whatever it is.
+        async fn recv_any(&mut self) -> FileTransferMessage {
+            loop {
+                let read = async {
+                    let opcode = self.0.read_u8().await.unwrap() & 0x0f;
+                    let len = match self.0.read_u8().await.unwrap() & 0x7f {
+                        126 => self.0.read_u16().await.unwrap() as usize,
+                        127 => self.0.read_u64().await.unwrap() as usize,
+                        len => len as usize,
+                    };
+                    let mut payload = vec![0; len];
+                    self.0.read_exact(&mut payload).await.unwrap();
+                    (opcode, payload)
+                };
+                let (opcode, payload) = tokio::time::timeout(Duration::from_secs(5), read)
+                    .await
+                    .expect("no message within 5 seconds");
+                if opcode == 1 {
+                    return serde_json::from_slice(&payload).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Pairs two fresh devices and returns their ids and tokens.
+    fn pair_two(discovery: &DiscoveryService) -> ((String, String), (String, String)) {
+        let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let first_token = discovery.bootstrap(&first, &discovery.bootstrap_pin().unwrap()).unwrap();
+        let Ok(PairingStart::Pending { pairing_id, pin, .. }) = discovery.begin_pairing(&second) else {
+            panic!("second device should need approval");
+        };
+        let (_, second_token) = discovery.approve_pairing(&pairing_id, &pin).unwrap();
+        ((first, first_token), (second, second_token))
+    }
+
+    /// Serves the WebSocket route on a free local port.
+    fn serve(
+        discovery: &Arc<DiscoveryService>,
+        sessions: &Arc<SessionRegistry>,
+    ) -> (std::net::SocketAddr, actix_web::dev::ServerHandle) {
+        let transfers = Arc::new(TransferManager::new(Arc::clone(sessions), Duration::from_secs(60)));
+        let server = {
+            let (discovery, sessions) = (Arc::clone(discovery), Arc::clone(sessions));
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(Arc::clone(&discovery)))
+                    .app_data(web::Data::new(Arc::clone(&sessions)))
+                    .app_data(web::Data::new(Arc::clone(&transfers)))
+                    .app_data(web::Data::new(WebSocketConfig::default()))
+                    .route("/api/ws", web::get().to(websocket_route))
+            })
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap()
+        };
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+        (addr, handle)
+    }
+
+    #[actix_rt::test]
+    async fn test_messages_are_routed_between_two_sessions() {
+        let sessions = Arc::new(SessionRegistry::new());
+        let discovery =
+            Arc::new(DiscoveryService::new(TrustStore::in_memory()).with_sessions(Arc::clone(&sessions)));
+        let ((sender, sender_token), (receiver, receiver_token)) = pair_two(&discovery);
+        let (addr, handle) = serve(&discovery, &sessions);
+
+        let mut a = Client::connect(addr, &sender_token).await;
+        let mut b = Client::connect(addr, &receiver_token).await;
+
+        a.send(&FileTransferMessage::TransferRequest {
+            file_id: "t1".to_string(),
+            filename: "note.txt".to_string(),
+            size: 4,
+            sender_id: String::new(),
+            receiver_id: receiver.clone(),
+            timestamp: Utc::now(),
+        })
+        .await;
+        assert!(matches!(
+            b.recv().await,
+            FileTransferMessage::TransferRequest { file_id, sender_id, .. } if file_id == "t1" && sender_id == sender
+        ));
+
+        b.send(&FileTransferMessage::TransferAccept {
+            file_id: "t1".to_string(),
+            timestamp: Utc::now(),
+        })
+        .await;
+        assert!(matches!(a.recv().await, FileTransferMessage::TransferAccept { file_id, .. } if file_id == "t1"));
+
+        a.send(&FileTransferMessage::FileTransferInit {
+            transfer_id: "t1".to_string(),
+            filename: "note.txt".to_string(),
+            file_size: 4,
+            sender_id: String::new(),
+            receiver_id: receiver.clone(),
+        })
+        .await;
+        assert!(matches!(
+            b.recv().await,
+            FileTransferMessage::FileTransferInit { sender_id, .. } if sender_id == sender
+        ));
+
+        a.send(&FileTransferMessage::FileChunk {
+            transfer_id: "t1".to_string(),
+            chunk_index: 0,
+            total_chunks: 1,
+            data: "bm90ZQ==".to_string(),
+            receiver_id: receiver.clone(),
+        })
+        .await;
+        assert!(matches!(b.recv().await, FileTransferMessage::FileChunk { data, .. } if data == "bm90ZQ=="));
+
+        b.send(&FileTransferMessage::ChunkAck {
+            transfer_id: "t1".to_string(),
+            chunk_index: 0,
+        })
+        .await;
+        assert!(matches!(a.recv().await, FileTransferMessage::ChunkAck { chunk_index: 0, .. }));
+
+        a.send(&FileTransferMessage::FileTransferComplete {
+            transfer_id: "t1".to_string(),
+            receiver_id: receiver.clone(),
+        })
+        .await;
+        assert!(matches!(b.recv().await, FileTransferMessage::FileTransferComplete { .. }));
+
+        // Nothing is echoed back to the sender, and what cannot be routed is answered with an error
+        a.send(&FileTransferMessage::ServerShutdown {
+            grace_secs: 0,
+            timestamp: Utc::now(),
+        })
+        .await;
+        assert!(matches!(
+            a.recv().await,
+            FileTransferMessage::Error { message, .. } if message == "Only the server sends this message"
+        ));
+
+        handle.stop(false).await;
+    }
+    #[actix_rt::test]
+    async fn test_trusted_sessions_see_devices_join_and_leave() {
+        let sessions = Arc::new(SessionRegistry::new());
+        let discovery = Arc::new(
+            DiscoveryService::new(TrustStore::in_memory())
+                .with_sessions(Arc::clone(&sessions))
+                .with_reconnect_grace(chrono::Duration::milliseconds(300)),
+        );
+        let ((_, watcher_token), (joiner, joiner_token)) = pair_two(&discovery);
+        let (addr, handle) = serve(&discovery, &sessions);
+
+        let mut watcher = Client::connect(addr, &watcher_token).await;
+        assert!(matches!(watcher.recv_any().await, FileTransferMessage::DeviceList { .. }));
+
+        let joined = Client::connect(addr, &joiner_token).await;
+        loop {
+            match watcher.recv_any().await {
+                FileTransferMessage::DeviceJoined { device, .. } if device.id == joiner => break,
+                FileTransferMessage::DeviceJoined { device, .. } => panic!("unexpected join of {}", device.id),
+                _ => continue,
+            }
+        }
+
+        // Dropping the socket without a close frame leaves the device waiting to reconnect
+        drop(joined);
+        discovery.start_pruning(Duration::from_millis(50));
+        loop {
+            match watcher.recv_any().await {
+                FileTransferMessage::DeviceLeft { device_id, .. } => {
+                    assert_eq!(device_id, joiner);
+                    break;
+                }
+                FileTransferMessage::DeviceUpdated { device, .. } => assert_eq!(device.id, joiner),
+                _ => continue,
+            }
+        }
+        assert!(!discovery.trusted_device_ids().contains(&joiner));
+
+        handle.stop(false).await;
+    }
+}
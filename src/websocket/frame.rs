@@ -0,0 +1,192 @@
+use actix_http::ws::Item;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use bytes::{Bytes, BytesMut};
+use std::io;
+
+/// Version byte every binary chunk frame starts with
+pub const CHUNK_FRAME_VERSION: u8 = 1;
+
+/// A file chunk sent as a binary WebSocket message instead of a base64 `FileChunk`:
+///
+/// | Bytes | Field |
+/// | --- | --- |
+/// | 1 | version, `1` |
+/// | 1 | length `N` of the transfer id |
+/// | N | transfer id, UTF-8 |
+/// | 1 | length `M` of the receiver id |
+/// | M | receiver id, UTF-8 |
+/// | 4 | chunk index, big-endian |
+/// | rest | chunk contents |
+///
+/// The server reads the header to route the frame and relays the frame unchanged.
+#[derive(Debug)]
+pub struct ChunkFrame {
+    pub transfer_id: String,
+    pub receiver_id: String,
+    pub chunk_index: u32,
+    pub data: Bytes,
+}
+
+impl ChunkFrame {
+    /// Reads the header of `frame`; `data` shares the frame's buffer.
+    pub fn decode(frame: &Bytes) -> io::Result<Self> {
+        let mut pos = 0;
+        let mut take = |len: usize| {
+            let field = frame
+                .get(pos..pos + len)
+                .ok_or_else(|| invalid("chunk frame header is truncated"))?;
+            pos += len;
+            Ok::<_, io::Error>(field)
+        };
+
+        if take(1)?[0] != CHUNK_FRAME_VERSION {
+            return Err(invalid("unsupported chunk frame version"));
+        }
+        let len = take(1)?[0] as usize;
+        let transfer_id = utf8(take(len)?)?;
+        let len = take(1)?[0] as usize;
+        let receiver_id = utf8(take(len)?)?;
+        let chunk_index = u32::from_be_bytes(take(4)?.try_into().unwrap());
+        if transfer_id.is_empty() || receiver_id.is_empty() {
+            return Err(invalid("chunk frame has an empty transfer or receiver id"));
+        }
+
+        Ok(Self {
+            transfer_id,
+            receiver_id,
+            chunk_index,
+            data: frame.slice(pos..),
+        })
+    }
+
+    /// Builds a frame, as clients do. Ids longer than 255 bytes cannot be encoded.
+    #[cfg(test)]
+    pub fn encode(transfer_id: &str, receiver_id: &str, chunk_index: u32, data: &[u8]) -> Bytes {
+        let mut frame = vec![CHUNK_FRAME_VERSION, transfer_id.len() as u8];
+        frame.extend_from_slice(transfer_id.as_bytes());
+        frame.push(receiver_id.len() as u8);
+        frame.extend_from_slice(receiver_id.as_bytes());
+        frame.extend_from_slice(&chunk_index.to_be_bytes());
+        frame.extend_from_slice(data);
+        frame.into()
+    }
+}
+
+fn utf8(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("chunk frame id is not UTF-8"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A complete message put together from continuation frames.
+#[derive(Debug)]
+pub enum Assembled {
+    Text(String),
+    Binary(Bytes),
+}
+
+/// Joins fragmented messages back together, refusing any larger than `max_size`.
+pub struct FrameAssembler {
+    max_size: usize,
+    /// Whether the message being assembled is text; `None` between messages
+    text: Option<bool>,
+    buffer: BytesMut,
+}
+
+impl FrameAssembler {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            text: None,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Adds a fragment, returning the message once its last fragment has arrived. On error the
+    /// connection should be closed with the returned reason.
+    pub fn push(&mut self, item: Item) -> Result<Option<Assembled>, CloseReason> {
+        let (first, data, last) = match item {
+            Item::FirstText(data) => (Some(true), data, false),
+            Item::FirstBinary(data) => (Some(false), data, false),
+            Item::Continue(data) => (None, data, false),
+            Item::Last(data) => (None, data, true),
+        };
+
+        match (first, self.text) {
+            (Some(_), Some(_)) => return Err(protocol("A new message started before the last one ended")),
+            (None, None) => return Err(protocol("Continuation frame without a message to continue")),
+            (Some(text), None) => self.text = Some(text),
+            (None, Some(_)) => {}
+        }
+
+        if self.buffer.len() + data.len() > self.max_size {
+            self.text = None;
+            self.buffer = BytesMut::new();
+            return Err(CloseReason {
+                code: CloseCode::Size,
+                description: Some(format!("Message is larger than {} bytes", self.max_size)),
+            });
+        }
+        self.buffer.extend_from_slice(&data);
+        if !last {
+            return Ok(None);
+        }
+
+        let message = self.buffer.split().freeze();
+        match self.text.take() {
+            Some(true) => String::from_utf8(message.to_vec())
+                .map(|text| Some(Assembled::Text(text)))
+                .map_err(|_| CloseReason {
+                    code: CloseCode::Invalid,
+                    description: Some("Text message is not UTF-8".to_string()),
+                }),
+            _ => Ok(Some(Assembled::Binary(message))),
+        }
+    }
+}
+
+fn protocol(description: &str) -> CloseReason {
+    CloseReason {
+        code: CloseCode::Protocol,
+        description: Some(description.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_frames_and_reassembly() {
+        let frame = ChunkFrame::encode("transfer-1", "phone", 7, b"hello");
+        let chunk = ChunkFrame::decode(&frame).unwrap();
+        assert_eq!(chunk.transfer_id, "transfer-1");
+        assert_eq!(chunk.receiver_id, "phone");
+        assert_eq!(chunk.chunk_index, 7);
+        assert_eq!(chunk.data, "hello");
+
+        assert!(ChunkFrame::decode(&frame.slice(..frame.len() - 9)).is_err());
+        assert!(ChunkFrame::decode(&Bytes::from_static(&[2, 0, 0, 0, 0, 0, 0])).is_err());
+        assert!(ChunkFrame::decode(&ChunkFrame::encode("", "phone", 0, b"")).is_err());
+
+        let mut assembler = FrameAssembler::new(32);
+        assert!(assembler.push(Item::FirstBinary(frame.slice(..4))).unwrap().is_none());
+        assert!(assembler.push(Item::Continue(frame.slice(4..10))).unwrap().is_none());
+        match assembler.push(Item::Last(frame.slice(10..))) {
+            Ok(Some(Assembled::Binary(message))) => assert_eq!(message, frame),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(assembler.push(Item::FirstText(Bytes::from_static(b"{\"type\""))).unwrap().is_none());
+        assert!(matches!(
+            assembler.push(Item::Last(Bytes::from_static(b":1}"))),
+            Ok(Some(Assembled::Text(text))) if text == "{\"type\":1}"
+        ));
+
+        assert_eq!(assembler.push(Item::Continue(Bytes::new())).unwrap_err().code, CloseCode::Protocol);
+        assembler.push(Item::FirstBinary(Bytes::from_static(&[0; 20]))).unwrap();
+        assert_eq!(assembler.push(Item::Last(Bytes::from_static(&[0; 20]))).unwrap_err().code, CloseCode::Size);
+    }
+}
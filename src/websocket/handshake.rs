@@ -0,0 +1,64 @@
+use crate::models::device::Feature;
+
+/// Newest version of the WebSocket protocol this server speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version of the WebSocket protocol this server speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Features the server can relay. Compression and encryption are applied by the devices; the
+/// server only needs to know both sides agreed on them.
+pub const SERVER_FEATURES: &[Feature] = &[Feature::BinaryChunks, Feature::Compression, Feature::Encryption];
+
+/// Features a client that never sends `Hello` is assumed to have, as binary chunks were accepted
+/// before there was a handshake.
+pub const LEGACY_FEATURES: &[Feature] = &[Feature::BinaryChunks];
+
+/// Picks the protocol version and features to use with a client that speaks versions
+/// `min_version` to `version` and supports `features`. The error explains why the client cannot
+/// be served.
+pub fn negotiate(version: u32, min_version: Option<u32>, features: &[Feature]) -> Result<(u32, Vec<Feature>), String> {
+    let min_version = min_version.unwrap_or(version);
+    if min_version > version {
+        return Err(format!(
+            "min_protocol_version {} is newer than protocol_version {}",
+            min_version, version
+        ));
+    }
+
+    let agreed = version.min(PROTOCOL_VERSION);
+    if agreed < min_version || agreed < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Client speaks protocol versions {} to {}, but this server speaks {} to {}",
+            min_version, version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+
+    let common = SERVER_FEATURES
+        .iter()
+        .filter(|feature| features.contains(feature))
+        .copied()
+        .collect();
+    Ok((agreed, common))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiates_version_and_common_features() {
+        let features: Vec<Feature> = serde_json::from_str(r#"["encryption", "binary_chunks", "telepathy"]"#).unwrap();
+        assert_eq!(features[2], Feature::Unknown);
+        let (version, common) = negotiate(PROTOCOL_VERSION, None, &features).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(common, [Feature::BinaryChunks, Feature::Encryption]);
+
+        // A newer client that can still speak this server's version is served with it
+        assert_eq!(negotiate(PROTOCOL_VERSION + 2, Some(MIN_PROTOCOL_VERSION), &[]).unwrap().0, PROTOCOL_VERSION);
+
+        let error = negotiate(PROTOCOL_VERSION + 2, None, &[]).unwrap_err();
+        assert!(error.contains("this server speaks 1 to 1"), "{}", error);
+        assert!(negotiate(MIN_PROTOCOL_VERSION - 1, None, &[]).is_err());
+        assert!(negotiate(1, Some(2), &[]).is_err());
+    }
+}
@@ -1,18 +1,57 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use utoipa::ToSchema;
 
-use crate::models::device::DeviceInfo;
+use crate::models::device::{DeviceInfo, Feature};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A WebSocket message, sent in either direction as a JSON text frame. `type` names the variant.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(tag = "type")]
 pub enum FileTransferMessage {
+    /// Sent by the client when it connects, before anything else. Clients that never send it
+    /// are served as protocol version 1 with `binary_chunks`.
+    Hello {
+        /// Newest protocol version the client speaks
+        protocol_version: u32,
+        /// Oldest protocol version the client speaks; `protocol_version` when omitted
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_protocol_version: Option<u32>,
+        client_version: String,
+        #[serde(default)]
+        features: Vec<Feature>,
+    },
+    /// Answer to `Hello` with the protocol version and the features both sides support. A
+    /// client the server cannot serve gets an `Error` instead and is disconnected.
+    Welcome {
+        protocol_version: u32,
+        server_version: String,
+        features: Vec<Feature>,
+        timestamp: DateTime<Utc>,
+    },
     DeviceDiscovery {
         timestamp: DateTime<Utc>,
     },
+    /// Every known device. Sent to a trusted device when it connects or is paired, and in
+    /// answer to `DeviceDiscovery`; changes after that arrive as the events below.
     DeviceList {
         devices: Vec<DeviceInfo>,
         timestamp: DateTime<Utc>,
     },
+    /// A device connected for the first time
+    DeviceJoined {
+        device: DeviceInfo,
+        timestamp: DateTime<Utc>,
+    },
+    /// A known device reconnected, dropped off, was renamed or became trusted
+    DeviceUpdated {
+        device: DeviceInfo,
+        timestamp: DateTime<Utc>,
+    },
+    /// A device closed its connection, or did not reconnect in time
+    DeviceLeft {
+        device_id: String,
+        timestamp: DateTime<Utc>,
+    },
     FileTransferInit {
         transfer_id: String,
         filename: String,
@@ -20,6 +59,8 @@ pub enum FileTransferMessage {
         sender_id: String,
         receiver_id: String,
     },
+    /// `data` is base64. Devices can send chunks without the encoding overhead as binary
+    /// frames instead; see `websocket::frame::ChunkFrame`.
     FileChunk {
         transfer_id: String,
         chunk_index: usize,
@@ -27,32 +68,130 @@ pub enum FileTransferMessage {
         data: String,
         receiver_id: String,
     },
+    /// Sent by the receiver once it has every chunk of a transfer up to `chunk_index`;
+    /// relayed to the sender. A sender may have at most 16 chunks unacknowledged, counting
+    /// `FileChunk`s and binary chunk frames alike; further chunks are refused with an `Error`.
+    ChunkAck {
+        transfer_id: String,
+        chunk_index: u64,
+    },
     FileTransferComplete {
         transfer_id: String,
         receiver_id: String,
     },
+    /// Asks `receiver_id` to accept a file. `file_id` is chosen by the sender and becomes the
+    /// `transfer_id` of the messages that follow.
     TransferRequest {
         file_id: String,
         filename: String,
         size: u64,
+        /// Filled in by the server
+        #[serde(default)]
+        sender_id: String,
+        receiver_id: String,
         timestamp: DateTime<Utc>,
     },
+    /// Sent by the receiver; relayed to the sender
     TransferAccept {
         file_id: String,
         timestamp: DateTime<Utc>,
     },
+    /// Sent by the receiver; relayed to the sender
     TransferReject {
         file_id: String,
         timestamp: DateTime<Utc>,
     },
+    /// Sent by either side to abandon a transfer; relayed to the other side
+    TransferCancel {
+        file_id: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// Sent by the server to both devices while a file is streamed or uploaded, at most twice
+    /// a second and once more when the last byte is through
     TransferProgress {
         file_id: String,
         bytes_transferred: u64,
+        /// `0` when the size is unknown
         total_bytes: u64,
+        /// Average since streaming started
+        #[serde(default)]
+        bytes_per_second: u64,
+        /// Seconds left at the current rate; absent until there is a rate and a size
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        eta_secs: Option<u64>,
+        timestamp: DateTime<Utc>,
+    },
+    /// Sent to an unpaired device while no device is trusted yet. The device must answer with
+    /// `BootstrapPairing` and the setup PIN the server printed to its log. `resume_token`
+    /// reconnects as `device_id` until pairing completes and is replaced on every connection.
+    BootstrapRequired {
+        device_id: String,
+        resume_token: String,
+    },
+    /// Pairs the first device with the setup PIN from the server log; answered with
+    /// `PairingApproved`. The PIN is locked after three wrong attempts until the server restarts.
+    BootstrapPairing {
+        pin: String,
+    },
+    /// Sent to an unpaired device: show `pin` (or a QR code of it) for a trusted device to enter.
+    /// `resume_token` reconnects as `device_id` until pairing completes and is replaced on every
+    /// connection.
+    PairingRequired {
+        device_id: String,
+        resume_token: String,
+        pairing_id: String,
+        pin: String,
+        expires_at: DateTime<Utc>,
+    },
+    /// Sent to trusted devices when an unpaired device asks to join
+    PairingRequest {
+        pairing_id: String,
+        device_id: String,
+        device_name: String,
+        timestamp: DateTime<Utc>,
+    },
+    PairingApprove {
+        pairing_id: String,
+        pin: String,
+    },
+    PairingReject {
+        pairing_id: String,
+    },
+    /// Sent to a newly trusted device. Present `token` when reconnecting, as
+    /// `Authorization: Bearer <token>` or as a `windrop-token.<token>` WebSocket subprotocol.
+    PairingApproved {
+        device_id: String,
+        token: String,
+    },
+    /// Sent to every session when the server begins shutting down. Uploads in progress may
+    /// finish for `grace_secs`; the socket is closed after that.
+    ServerShutdown {
+        grace_secs: u64,
         timestamp: DateTime<Utc>,
     },
     Error {
         message: String,
+        /// The transfer the error is about, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transfer_id: Option<String>,
         timestamp: DateTime<Utc>,
     },
-}
\ No newline at end of file
+}
+
+impl FileTransferMessage {
+    pub fn error(message: impl Into<String>) -> Self {
+        FileTransferMessage::Error {
+            message: message.into(),
+            transfer_id: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub fn transfer_error(transfer_id: &str, message: impl Into<String>) -> Self {
+        FileTransferMessage::Error {
+            message: message.into(),
+            transfer_id: Some(transfer_id.to_string()),
+            timestamp: Utc::now(),
+        }
+    }
+}
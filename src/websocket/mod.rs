@@ -1,2 +1,6 @@
 pub mod connection; 
-pub mod message;
\ No newline at end of file
+pub mod frame;
+pub mod handshake;
+pub mod message;
+pub mod registry;
+pub mod transfers;
@@ -0,0 +1,85 @@
+use actix::Addr;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use super::connection::{Deliver, FileTransferWs, ServerClosing};
+use super::message::FileTransferMessage;
+
+/// Live WebSocket sessions keyed by the device id they registered with.
+pub struct SessionRegistry {
+    sessions: RwLock<HashMap<String, Addr<FileTransferWs>>>,
+    closing: AtomicBool,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self {
+            sessions: RwLock::new(HashMap::new()),
+            closing: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether shutdown has started, after which no new sessions are accepted.
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting sessions and tells every connected device the server is going away.
+    pub fn begin_shutdown(&self, grace: Duration) {
+        self.closing.store(true, Ordering::SeqCst);
+        let notice = FileTransferMessage::ServerShutdown {
+            grace_secs: grace.as_secs(),
+            timestamp: Utc::now(),
+        };
+        for addr in self.sessions.read().unwrap().values() {
+            addr.do_send(Deliver(notice.clone()));
+        }
+    }
+
+    /// Closes every session with a close frame and waits up to `wait` for them to go away.
+    pub async fn close_all(&self, wait: Duration) {
+        for addr in self.sessions.read().unwrap().values() {
+            addr.do_send(ServerClosing);
+        }
+
+        let deadline = Instant::now() + wait;
+        while !self.sessions.read().unwrap().is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Registers `addr` for `device_id`, returning the session it replaces, if any.
+    pub fn register(&self, device_id: String, addr: Addr<FileTransferWs>) -> Option<Addr<FileTransferWs>> {
+        self.sessions.write().unwrap().insert(device_id, addr)
+    }
+
+    /// Removes the session for `device_id` if it is still `addr`, i.e. it has not been replaced by
+    /// a reconnect. Returns whether it was removed.
+    pub fn unregister(&self, device_id: &str, addr: &Addr<FileTransferWs>) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        if sessions.get(device_id) == Some(addr) {
+            sessions.remove(device_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of registered sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    /// Returns the session for `device_id` if it is registered and its actor is still running.
+    pub fn get(&self, device_id: &str) -> Option<Addr<FileTransferWs>> {
+        self.sessions
+            .read()
+            .unwrap()
+            .get(device_id)
+            .filter(|addr| addr.connected())
+            .cloned()
+    }
+}
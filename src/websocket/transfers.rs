@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use super::connection::Deliver;
+use super::message::FileTransferMessage;
+use super::registry::SessionRegistry;
+
+/// How often unanswered transfer requests are looked for
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Least time between two `TransferProgress` events of one transfer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// Chunks a sender may relay ahead of the receiver's `ChunkAck`s, so a slow receiver holds the
+/// sender back instead of piling chunks up in the server
+pub const MAX_UNACKED_CHUNKS: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// `TransferRequest` sent, waiting for the receiver to answer
+    Requested,
+    /// The receiver sent `TransferAccept`
+    Accepted,
+    /// The sender sent `FileTransferInit` and chunks may follow
+    Streaming,
+    Completed,
+    /// A device dropped out, or the receiver did not answer in time
+    Failed,
+    /// Rejected by the receiver or cancelled by either side
+    Cancelled,
+}
+
+impl TransferState {
+    fn is_finished(self) -> bool {
+        matches!(self, Self::Completed | Self::Failed | Self::Cancelled)
+    }
+}
+
+impl fmt::Display for TransferState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Requested => "requested",
+            Self::Accepted => "accepted",
+            Self::Streaming => "streaming",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Something a device did to a transfer.
+#[derive(Debug, Clone, Copy)]
+pub enum TransferEvent {
+    Accept,
+    Reject,
+    /// Streaming starts; carries the file size, `0` when unknown
+    Start(u64),
+    Chunk(u64),
+    Complete,
+    Cancel,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub id: String,
+    pub sender_id: String,
+    pub receiver_id: String,
+    pub state: TransferState,
+    /// Size of the file in bytes, `0` when unknown
+    size: u64,
+    /// Bytes relayed or uploaded so far
+    bytes: u64,
+    /// Index the next chunk must have
+    next_chunk: u64,
+    /// Chunks the receiver has acknowledged, counted from the first
+    acked_chunks: u64,
+    requested_at: Instant,
+    started_at: Option<Instant>,
+    reported_at: Option<Instant>,
+}
+
+impl Transfer {
+    /// The other device taking part in the transfer.
+    pub fn peer_of(&self, device_id: &str) -> &str {
+        if device_id == self.sender_id {
+            &self.receiver_id
+        } else {
+            &self.sender_id
+        }
+    }
+
+    fn start(&mut self, size: u64) {
+        if size > 0 {
+            self.size = size;
+        }
+        self.bytes = 0;
+        self.started_at = Some(Instant::now());
+        self.reported_at = None;
+    }
+}
+
+/// Tracks the transfers between devices, so only the messages a transfer's state allows, from
+/// the device allowed to send them, are relayed. Transfer ids are the `file_id` of the
+/// `TransferRequest`, used as `transfer_id` by the messages that follow it.
+///
+/// It also counts the bytes of each transfer and sends both devices `TransferProgress` events.
+pub struct TransferManager {
+    transfers: Mutex<HashMap<String, Transfer>>,
+    sessions: Arc<SessionRegistry>,
+    request_timeout: Duration,
+}
+
+impl TransferManager {
+    pub fn new(sessions: Arc<SessionRegistry>, request_timeout: Duration) -> Self {
+        Self {
+            transfers: Mutex::new(HashMap::new()),
+            sessions,
+            request_timeout,
+        }
+    }
+
+    /// Records a transfer `sender_id` asks `receiver_id` to accept.
+    pub fn request(
+        &self,
+        id: &str,
+        sender_id: &str,
+        receiver_id: &str,
+        filename: &str,
+        size: u64,
+    ) -> Result<(), String> {
+        if sender_id == receiver_id {
+            return Err("A device cannot send a transfer to itself".to_string());
+        }
+        let mut transfers = self.transfers.lock().unwrap();
+        if transfers.contains_key(id) {
+            return Err(format!("Transfer {} already exists", id));
+        }
+        transfers.insert(
+            id.to_string(),
+            Transfer {
+                id: id.to_string(),
+                sender_id: sender_id.to_string(),
+                receiver_id: receiver_id.to_string(),
+                state: TransferState::Requested,
+                size,
+                bytes: 0,
+                next_chunk: 0,
+                acked_chunks: 0,
+                requested_at: Instant::now(),
+                started_at: None,
+                reported_at: None,
+            },
+        );
+        log::info!("Transfer {} of {:?} requested by {} for {}", id, filename, sender_id, receiver_id);
+        Ok(())
+    }
+
+    /// Applies what `device_id` did to transfer `id`, returning the device the message should be
+    /// relayed to. `receiver_id` is the receiver named in the message, when it names one.
+    pub fn apply(
+        &self,
+        id: &str,
+        device_id: &str,
+        receiver_id: Option<&str>,
+        event: TransferEvent,
+    ) -> Result<String, String> {
+        use TransferEvent::*;
+        use TransferState::*;
+
+        let mut transfers = self.transfers.lock().unwrap();
+        let transfer = transfers
+            .get_mut(id)
+            .ok_or_else(|| format!("Unknown transfer {}", id))?;
+
+        let by_sender = device_id == transfer.sender_id;
+        let by_receiver = device_id == transfer.receiver_id;
+        let allowed_party = match event {
+            Accept | Reject => by_receiver,
+            Start(_) | Chunk(_) | Complete => by_sender,
+            Cancel => by_sender || by_receiver,
+        };
+        if !allowed_party {
+            return Err(format!("Device is not allowed to send this message for transfer {}", id));
+        }
+        if receiver_id.is_some_and(|receiver_id| receiver_id != transfer.receiver_id) {
+            return Err(format!("Transfer {} is addressed to another device", id));
+        }
+
+        let next = match (event, transfer.state) {
+            (Accept, Requested) => Accepted,
+            (Reject, Requested) => Cancelled,
+            (Start(size), Accepted) => {
+                transfer.start(size);
+                Streaming
+            }
+            (Chunk(index), Streaming) => {
+                if index != transfer.next_chunk {
+                    return Err(format!(
+                        "Transfer {} expected chunk {}, got {}",
+                        id, transfer.next_chunk, index
+                    ));
+                }
+                if transfer.next_chunk - transfer.acked_chunks >= MAX_UNACKED_CHUNKS {
+                    return Err(format!(
+                        "Transfer {} has {} chunks waiting for ChunkAck; resend chunk {} once the receiver catches up",
+                        id, MAX_UNACKED_CHUNKS, index
+                    ));
+                }
+                transfer.next_chunk += 1;
+                Streaming
+            }
+            (Complete, Streaming) => Completed,
+            (Cancel, _) => Cancelled,
+            (event, state) => {
+                return Err(format!("Transfer {} is {}; {:?} is not allowed", id, state, event));
+            }
+        };
+
+        let peer = transfer.peer_of(device_id).to_string();
+        if next != transfer.state {
+            transfer.state = next;
+            log::info!("Transfer {} is {}", id, next);
+        }
+        if next.is_finished() {
+            transfers.remove(id);
+        }
+        Ok(peer)
+    }
+
+    /// Records that the receiver of transfer `id` has every chunk up to `chunk_index`, freeing
+    /// the sender to relay more. Returns the sender to pass the acknowledgement on to, or `None`
+    /// when the transfer has already finished, as the last acknowledgements may arrive after
+    /// `FileTransferComplete`.
+    pub fn acknowledge(&self, id: &str, device_id: &str, chunk_index: u64) -> Result<Option<String>, String> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get_mut(id) else {
+            return Ok(None);
+        };
+        if device_id != transfer.receiver_id {
+            return Err(format!("Only the receiver can acknowledge chunks of transfer {}", id));
+        }
+        if transfer.state != TransferState::Streaming || chunk_index >= transfer.next_chunk {
+            return Err(format!("Transfer {} has not relayed chunk {}", id, chunk_index));
+        }
+
+        transfer.acked_chunks = transfer.acked_chunks.max(chunk_index + 1);
+        Ok(Some(transfer.sender_id.clone()))
+    }
+
+    /// Lets the sender upload the file of transfer `id` over HTTP instead of streaming it, in
+    /// place of `FileTransferInit` and its chunks. A failed upload may be retried, which starts
+    /// the count of bytes over.
+    pub fn begin_upload(&self, id: &str, device_id: &str, size: Option<u64>) -> Result<(), String> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let transfer = transfers
+            .get_mut(id)
+            .ok_or_else(|| format!("Unknown transfer {}", id))?;
+        if device_id != transfer.sender_id {
+            return Err(format!("Only the sender can upload the file of transfer {}", id));
+        }
+        if !matches!(transfer.state, TransferState::Accepted | TransferState::Streaming) {
+            return Err(format!("Transfer {} is {}; the file cannot be uploaded", id, transfer.state));
+        }
+
+        transfer.start(size.unwrap_or(transfer.size));
+        if transfer.state != TransferState::Streaming {
+            transfer.state = TransferState::Streaming;
+            log::info!("Transfer {} is {}", id, transfer.state);
+        }
+        Ok(())
+    }
+
+    /// Counts `bytes` more of transfer `id` and, at most every `PROGRESS_INTERVAL` and once the
+    /// whole file is through, tells both devices how far it got.
+    pub fn record_bytes(&self, id: &str, bytes: u64) {
+        let Some((progress, devices)) = self.progress(id, bytes, Instant::now()) else {
+            return;
+        };
+        for device_id in &devices {
+            if let Some(addr) = self.sessions.get(device_id) {
+                addr.do_send(Deliver(progress.clone()));
+            }
+        }
+    }
+
+    fn progress(&self, id: &str, bytes: u64, now: Instant) -> Option<(FileTransferMessage, [String; 2])> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let transfer = transfers.get_mut(id)?;
+        let started_at = *transfer.started_at.get_or_insert(now);
+        transfer.bytes += bytes;
+
+        let finished = transfer.size > 0 && transfer.bytes >= transfer.size;
+        let since_report = now.duration_since(transfer.reported_at.unwrap_or(started_at));
+        if !finished && since_report < PROGRESS_INTERVAL {
+            return None;
+        }
+        transfer.reported_at = Some(now);
+
+        let elapsed = now.duration_since(started_at).as_secs_f64();
+        let bytes_per_second = if elapsed > 0.0 {
+            (transfer.bytes as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let eta_secs = (bytes_per_second > 0 && transfer.size > 0)
+            .then(|| transfer.size.saturating_sub(transfer.bytes).div_ceil(bytes_per_second));
+
+        let progress = FileTransferMessage::TransferProgress {
+            file_id: transfer.id.clone(),
+            bytes_transferred: transfer.bytes,
+            total_bytes: transfer.size,
+            bytes_per_second,
+            eta_secs,
+            timestamp: Utc::now(),
+        };
+        Some((progress, [transfer.sender_id.clone(), transfer.receiver_id.clone()]))
+    }
+
+    /// Fails the transfers `device_id` takes part in, returning them so the other side can be
+    /// told.
+    pub fn fail_device(&self, device_id: &str) -> Vec<Transfer> {
+        self.remove_where(|transfer| transfer.sender_id == device_id || transfer.receiver_id == device_id)
+    }
+
+    /// Fails requests that went unanswered for longer than the timeout.
+    fn expire(&self, now: Instant) -> Vec<Transfer> {
+        let timeout = self.request_timeout;
+        self.remove_where(|transfer| {
+            transfer.state == TransferState::Requested && now.duration_since(transfer.requested_at) >= timeout
+        })
+    }
+
+    fn remove_where(&self, mut condition: impl FnMut(&Transfer) -> bool) -> Vec<Transfer> {
+        let mut removed = Vec::new();
+        self.transfers.lock().unwrap().retain(|_, transfer| {
+            if !condition(transfer) {
+                return true;
+            }
+            transfer.state = TransferState::Failed;
+            log::info!("Transfer {} is {}", transfer.id, transfer.state);
+            removed.push(transfer.clone());
+            false
+        });
+        removed
+    }
+
+    /// Periodically fails unanswered requests and tells both devices.
+    pub fn start_expiry(self: &Arc<Self>) {
+        let manager = Arc::clone(self);
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                for transfer in manager.expire(Instant::now()) {
+                    let message = FileTransferMessage::transfer_error(
+                        &transfer.id,
+                        format!("Transfer {} was not answered in time", transfer.id),
+                    );
+                    for device_id in [&transfer.sender_id, &transfer.receiver_id] {
+                        if let Some(addr) = manager.sessions.get(device_id) {
+                            addr.do_send(Deliver(message.clone()));
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TransferEvent::*;
+
+    #[test]
+    fn test_transfer_lifecycle_is_enforced() {
+        let manager = TransferManager::new(Arc::new(SessionRegistry::new()), Duration::from_secs(30));
+        manager.request("t1", "laptop", "phone", "photo.jpg", 0).unwrap();
+        assert!(manager.request("t1", "laptop", "phone", "photo.jpg", 0).is_err());
+
+        // Nothing can be streamed before the receiver accepts, and only it can accept
+        assert!(manager.apply("t1", "laptop", Some("phone"), Start(0)).is_err());
+        assert!(manager.apply("t1", "laptop", None, Accept).is_err());
+        assert_eq!(manager.apply("t1", "phone", None, Accept).unwrap(), "laptop");
+
+        assert!(manager.apply("t1", "laptop", Some("phone"), Chunk(0)).is_err());
+        assert!(manager.apply("t1", "laptop", Some("tablet"), Start(0)).is_err());
+        assert_eq!(manager.apply("t1", "laptop", Some("phone"), Start(0)).unwrap(), "phone");
+        manager.apply("t1", "laptop", Some("phone"), Chunk(0)).unwrap();
+        assert!(manager.apply("t1", "laptop", Some("phone"), Chunk(2)).is_err());
+        assert!(manager.apply("t1", "phone", Some("phone"), Chunk(1)).is_err());
+        manager.apply("t1", "laptop", Some("phone"), Chunk(1)).unwrap();
+        manager.apply("t1", "laptop", Some("phone"), Complete).unwrap();
+        assert_eq!(manager.apply("t1", "laptop", None, Cancel).unwrap_err(), "Unknown transfer t1");
+
+        manager.request("t2", "laptop", "phone", "a.txt", 0).unwrap();
+        assert_eq!(manager.apply("t2", "phone", None, Reject).unwrap(), "laptop");
+        assert!(manager.apply("t2", "phone", None, Accept).is_err());
+
+        manager.request("t3", "laptop", "phone", "b.txt", 0).unwrap();
+        manager.request("t4", "tablet", "laptop", "c.txt", 0).unwrap();
+        manager.request("t5", "tablet", "watch", "d.txt", 0).unwrap();
+        manager.apply("t5", "watch", None, Accept).unwrap();
+        let mut failed: Vec<_> = manager.fail_device("laptop").into_iter().map(|t| t.id).collect();
+        failed.sort();
+        assert_eq!(failed, ["t3", "t4"]);
+
+        // Only requests nobody answered expire
+        manager.request("t6", "tablet", "watch", "e.txt", 0).unwrap();
+        let expired = manager.expire(Instant::now() + Duration::from_secs(30));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, "t6");
+        assert_eq!(expired[0].state, TransferState::Failed);
+        assert_eq!(manager.apply("t5", "tablet", Some("watch"), Start(0)).unwrap(), "watch");
+    }
+
+    #[test]
+    fn test_chunks_wait_for_acks() {
+        let manager = TransferManager::new(Arc::new(SessionRegistry::new()), Duration::from_secs(30));
+        manager.request("t1", "laptop", "phone", "video.mp4", 0).unwrap();
+        manager.apply("t1", "phone", None, Accept).unwrap();
+        manager.apply("t1", "laptop", Some("phone"), Start(0)).unwrap();
+        assert!(manager.acknowledge("t1", "phone", 0).is_err());
+
+        for index in 0..MAX_UNACKED_CHUNKS {
+            manager.apply("t1", "laptop", Some("phone"), Chunk(index)).unwrap();
+        }
+        let refused = manager.apply("t1", "laptop", Some("phone"), Chunk(MAX_UNACKED_CHUNKS)).unwrap_err();
+        assert!(refused.contains("ChunkAck"), "{}", refused);
+
+        // Acknowledgements are cumulative and only the receiver's count
+        assert!(manager.acknowledge("t1", "laptop", 3).is_err());
+        assert!(manager.acknowledge("t1", "phone", MAX_UNACKED_CHUNKS).is_err());
+        assert_eq!(manager.acknowledge("t1", "phone", 1).unwrap().as_deref(), Some("laptop"));
+        manager.acknowledge("t1", "phone", 0).unwrap();
+        manager.apply("t1", "laptop", Some("phone"), Chunk(MAX_UNACKED_CHUNKS)).unwrap();
+        manager.apply("t1", "laptop", Some("phone"), Chunk(MAX_UNACKED_CHUNKS + 1)).unwrap();
+        assert!(manager.apply("t1", "laptop", Some("phone"), Chunk(MAX_UNACKED_CHUNKS + 2)).is_err());
+
+        manager.apply("t1", "laptop", Some("phone"), Complete).unwrap();
+        assert_eq!(manager.acknowledge("t1", "phone", MAX_UNACKED_CHUNKS + 1), Ok(None));
+    }
+
+    #[test]
+    fn test_progress_is_throttled_with_rate_and_eta() {
+        let manager = TransferManager::new(Arc::new(SessionRegistry::new()), Duration::from_secs(30));
+        manager.request("t1", "laptop", "phone", "video.mp4", 4000).unwrap();
+        manager.apply("t1", "phone", None, Accept).unwrap();
+        assert!(manager.begin_upload("t1", "phone", None).is_err());
+        manager.begin_upload("t1", "laptop", None).unwrap();
+        let start = manager.transfers.lock().unwrap()["t1"].started_at.unwrap();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert!(manager.progress("t1", 500, at(100)).is_none());
+        let (progress, devices) = manager.progress("t1", 500, at(1000)).unwrap();
+        assert_eq!(devices, ["laptop", "phone"]);
+        match progress {
+            FileTransferMessage::TransferProgress {
+                bytes_transferred,
+                total_bytes,
+                bytes_per_second,
+                eta_secs,
+                ..
+            } => {
+                assert_eq!((bytes_transferred, total_bytes), (1000, 4000));
+                assert_eq!(bytes_per_second, 1000);
+                assert_eq!(eta_secs, Some(3));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Throttled until the interval passes, except for the last bytes
+        assert!(manager.progress("t1", 1000, at(1200)).is_none());
+        assert!(manager.progress("t1", 1000, at(1600)).is_some());
+        let (progress, _) = manager.progress("t1", 1000, at(1700)).unwrap();
+        assert!(matches!(
+            progress,
+            FileTransferMessage::TransferProgress { bytes_transferred: 4000, eta_secs: Some(0), .. }
+        ));
+        assert!(manager.progress("unknown", 10, at(2000)).is_none());
+    }
+}